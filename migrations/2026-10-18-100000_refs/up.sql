@@ -0,0 +1,5 @@
+CREATE TABLE refs (
+  referrer VARCHAR NOT NULL REFERENCES store (store_path) ON DELETE CASCADE,
+  reference VARCHAR NOT NULL,
+  PRIMARY KEY (referrer, reference)
+)
@@ -0,0 +1,2 @@
+-- Seconds since the epoch. Paths registered before this column existed read as 0.
+ALTER TABLE store ADD COLUMN registered_at BIGINT NOT NULL DEFAULT 0
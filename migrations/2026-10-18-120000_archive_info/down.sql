@@ -0,0 +1,2 @@
+ALTER TABLE store DROP COLUMN archive_size;
+ALTER TABLE store DROP COLUMN archive_hash;
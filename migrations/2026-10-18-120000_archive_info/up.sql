@@ -0,0 +1,4 @@
+-- Hash and size of the canonical archive of the path, see src/archive.rs. NULL for paths
+-- registered before these columns existed.
+ALTER TABLE store ADD COLUMN archive_hash TEXT;
+ALTER TABLE store ADD COLUMN archive_size BIGINT;
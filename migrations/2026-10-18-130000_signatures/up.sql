@@ -0,0 +1,6 @@
+-- Signatures of paths, as <key name>:<base64>, see src/signing.rs
+CREATE TABLE signatures (
+    store_path TEXT NOT NULL REFERENCES store(store_path) ON DELETE CASCADE,
+    signature TEXT NOT NULL,
+    PRIMARY KEY (store_path, signature)
+)
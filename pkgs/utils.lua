@@ -1,4 +1,4 @@
----@type {package: fun(table): Package}
+---@type {package: fun(table): Package, env: fun(table): Env}
 local miq = require "miq"
 
 local f = miq.f
@@ -8,6 +8,9 @@ local x = {}
 ---@alias Package
 ---| { result: string, name: string, deps: table<string>, script: string }
 
+---@alias Env
+---| { result: string, name: string, paths: table<string>, ignore_collisions: boolean }
+
 x.ccBuilder = function(input)
 	local input = input
 	local result = miq.package {
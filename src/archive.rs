@@ -0,0 +1,366 @@
+//! Canonical serialization of a store path, similar to Nix's NAR.
+//!
+//! The archive only holds what makes two trees equivalent: entry names, file contents, the
+//! executable bit and symlink targets. Timestamps, owners and other permission bits are dropped,
+//! so that dumping the same tree twice always yields the same bytes.
+//!
+//! Every token is a string: a little-endian `u64` length, the bytes, and zero padding up to a
+//! multiple of 8 bytes. The grammar is:
+//!
+//! ```text
+//! archive   = "miq-archive-1" node
+//! node      = "(" "type" (regular | symlink | directory) ")"
+//! regular   = "regular" ["executable" ""] "contents" <bytes>
+//! symlink   = "symlink" "target" <string>
+//! directory = "directory" { "entry" "(" "name" <string> "node" node ")" }
+//! ```
+//!
+//! Directory entries are sorted by the bytes of their name.
+
+use std::ffi::OsStr;
+use std::fs::{File, Permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use color_eyre::eyre::{bail, ensure, Context};
+use color_eyre::Result;
+
+use crate::hash::{Integrity, IntegrityHasher};
+
+const MAGIC: &str = "miq-archive-1";
+
+/// Refuse to read strings longer than this, other than file contents
+const MAX_STRING_LEN: u64 = 4096;
+
+fn padding(len: u64) -> usize {
+    ((8 - len % 8) % 8) as usize
+}
+
+pub(crate) fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    w.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    w.write_all(bytes)?;
+    w.write_all(&[0; 8][..padding(bytes.len() as u64)])
+}
+
+pub(crate) fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    write_bytes(w, s.as_bytes())
+}
+
+fn dump_node<W: Write>(path: &Path, w: &mut W) -> Result<()> {
+    let meta = std::fs::symlink_metadata(path).wrap_err(format!("Reading {:?}", path))?;
+
+    write_str(w, "(")?;
+    write_str(w, "type")?;
+
+    if meta.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        write_str(w, "symlink")?;
+        write_str(w, "target")?;
+        write_bytes(w, target.as_os_str().as_bytes())?;
+    } else if meta.is_file() {
+        write_str(w, "regular")?;
+        if meta.permissions().mode() & 0o100 != 0 {
+            write_str(w, "executable")?;
+            write_str(w, "")?;
+        }
+        write_str(w, "contents")?;
+
+        let len = meta.len();
+        w.write_all(&len.to_le_bytes())?;
+        let mut file = File::open(path)?;
+        let copied = io::copy(&mut (&mut file).take(len), w)?;
+        ensure!(copied == len, "{:?} changed size while dumping it", path);
+        w.write_all(&[0; 8][..padding(len)])?;
+    } else if meta.is_dir() {
+        write_str(w, "directory")?;
+
+        let mut entries = std::fs::read_dir(path)?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+        for name in entries {
+            write_str(w, "entry")?;
+            write_str(w, "(")?;
+            write_str(w, "name")?;
+            write_bytes(w, name.as_bytes())?;
+            write_str(w, "node")?;
+            dump_node(&path.join(&name), w)?;
+            write_str(w, ")")?;
+        }
+    } else {
+        bail!("{:?} is not a regular file, directory or symlink", path);
+    }
+
+    write_str(w, ")")?;
+    Ok(())
+}
+
+/// Write the archive of `path` into `w`
+pub fn dump<P: AsRef<Path>, W: Write>(path: P, w: &mut W) -> Result<()> {
+    write_str(w, MAGIC)?;
+    dump_node(path.as_ref(), w)
+}
+
+/// Forwards writes, while hashing and counting the bytes
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: IntegrityHasher,
+    size: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: IntegrityHasher::default(),
+            size: 0,
+        }
+    }
+
+    pub fn finish(self) -> (W, Integrity, u64) {
+        (self.inner, self.hasher.finish(), self.size)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hash and size of the archive of `path`, without keeping the archive around
+pub fn hash_path<P: AsRef<Path>>(path: P) -> Result<(Integrity, u64)> {
+    let mut writer = HashingWriter::new(io::sink());
+    dump(path, &mut writer)?;
+    let (_, integrity, size) = writer.finish();
+    Ok((integrity, size))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn skip_padding<R: Read>(r: &mut R, len: u64) -> Result<()> {
+    let mut buf = [0; 8];
+    let pad = &mut buf[..padding(len)];
+    r.read_exact(pad)?;
+    ensure!(pad.iter().all(|b| *b == 0), "Non-zero padding in archive");
+    Ok(())
+}
+
+/// Read a string written by [write_bytes], refusing strings longer than `max_len`
+pub(crate) fn read_bytes<R: Read>(r: &mut R, max_len: u64) -> Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    ensure!(len <= max_len, "String of {} bytes in archive", len);
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf)?;
+    skip_padding(r, len)?;
+    Ok(buf)
+}
+
+struct Reader<R> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        read_bytes(&mut self.inner, MAX_STRING_LEN)
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        let got = self.read_bytes()?;
+        ensure!(
+            got == token.as_bytes(),
+            "Expected {:?} in archive, got {:?}",
+            token,
+            String::from_utf8_lossy(&got)
+        );
+        Ok(())
+    }
+
+    fn restore_node(&mut self, path: &Path) -> Result<()> {
+        self.expect("(")?;
+        self.expect("type")?;
+
+        match self.read_bytes()?.as_slice() {
+            b"symlink" => {
+                self.expect("target")?;
+                let target = self.read_bytes()?;
+                std::os::unix::fs::symlink(OsStr::from_bytes(&target), path)?;
+                self.expect(")")?;
+            }
+            b"regular" => {
+                let mut token = self.read_bytes()?;
+                let executable = token == b"executable";
+                if executable {
+                    self.expect("")?;
+                    token = self.read_bytes()?;
+                }
+                ensure!(token == b"contents", "Expected \"contents\" in archive");
+
+                let len = read_u64(&mut self.inner)?;
+                let mut file = File::create(path).wrap_err(format!("Creating {:?}", path))?;
+                let copied = io::copy(&mut (&mut self.inner).take(len), &mut file)?;
+                ensure!(copied == len, "Archive ended in the middle of {:?}", path);
+                skip_padding(&mut self.inner, len)?;
+
+                let mode = if executable { 0o555 } else { 0o444 };
+                file.set_permissions(Permissions::from_mode(mode))?;
+                self.expect(")")?;
+            }
+            b"directory" => {
+                std::fs::create_dir(path).wrap_err(format!("Creating {:?}", path))?;
+                let mut previous: Option<Vec<u8>> = None;
+
+                loop {
+                    match self.read_bytes()?.as_slice() {
+                        b")" => break,
+                        b"entry" => {}
+                        other => bail!(
+                            "Expected \"entry\" in archive, got {:?}",
+                            String::from_utf8_lossy(other)
+                        ),
+                    }
+
+                    self.expect("(")?;
+                    self.expect("name")?;
+                    let name = self.read_bytes()?;
+                    ensure!(
+                        !name.is_empty()
+                            && name != b"."
+                            && name != b".."
+                            && !name.contains(&b'/')
+                            && !name.contains(&0),
+                        "Invalid entry name {:?} in archive",
+                        String::from_utf8_lossy(&name)
+                    );
+                    ensure!(
+                        previous.as_ref().is_none_or(|p| *p < name),
+                        "Archive entries are not sorted"
+                    );
+
+                    self.expect("node")?;
+                    self.restore_node(&path.join(OsStr::from_bytes(&name)))?;
+                    self.expect(")")?;
+                    previous = Some(name);
+                }
+            }
+            other => bail!(
+                "Unknown node type {:?} in archive",
+                String::from_utf8_lossy(other)
+            ),
+        }
+
+        Ok(())
+    }
+}
+
+/// Recreate the tree stored in the archive read from `r` at `path`, which must not exist
+pub fn restore<R: Read, P: AsRef<Path>>(r: R, path: P) -> Result<()> {
+    let mut reader = Reader { inner: r };
+    reader.expect(MAGIC).wrap_err("Reading archive header")?;
+    reader.restore_node(path.as_ref())
+}
+
+#[cfg(test)]
+fn sample_tree(root: &Path) {
+    std::fs::create_dir_all(root.join("bin")).unwrap();
+    std::fs::write(root.join("bin/hello"), "#!/bin/sh\necho hello\n").unwrap();
+    std::fs::set_permissions(root.join("bin/hello"), Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(root.join("README"), "hello world").unwrap();
+    std::fs::create_dir(root.join("empty")).unwrap();
+    std::os::unix::fs::symlink("bin/hello", root.join("link")).unwrap();
+}
+
+#[test]
+fn test_archive_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let original = dir.path().join("original");
+    sample_tree(&original);
+
+    let mut archive = Vec::new();
+    dump(&original, &mut archive).unwrap();
+
+    let restored = dir.path().join("restored");
+    restore(archive.as_slice(), &restored).unwrap();
+
+    let mut archive_again = Vec::new();
+    dump(&restored, &mut archive_again).unwrap();
+    assert_eq!(archive, archive_again);
+
+    assert_eq!(
+        std::fs::read_link(restored.join("link")).unwrap(),
+        Path::new("bin/hello")
+    );
+    let mode = std::fs::metadata(restored.join("bin/hello"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o555);
+}
+
+#[test]
+fn test_archive_ignores_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a");
+    sample_tree(&a);
+    let b = dir.path().join("b");
+    sample_tree(&b);
+    // Other permission bits and timestamps don't matter, only the executable bit
+    std::fs::set_permissions(b.join("README"), Permissions::from_mode(0o600)).unwrap();
+    std::fs::set_permissions(b.join("bin/hello"), Permissions::from_mode(0o700)).unwrap();
+
+    let (hash_a, size_a) = hash_path(&a).unwrap();
+    let (hash_b, size_b) = hash_path(&b).unwrap();
+    assert_eq!(hash_a, hash_b);
+    assert_eq!(size_a, size_b);
+
+    std::fs::set_permissions(b.join("README"), Permissions::from_mode(0o700)).unwrap();
+    assert_ne!(hash_path(&b).unwrap().0, hash_a);
+}
+
+#[test]
+fn test_archive_golden() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("file");
+    std::fs::write(&file, "hello world").unwrap();
+
+    let mut archive = Vec::new();
+    dump(&file, &mut archive).unwrap();
+    assert_eq!(archive.len() % 8, 0);
+
+    let (integrity, size) = hash_path(&file).unwrap();
+    assert_eq!(size, 128);
+    assert_eq!(size, archive.len() as u64);
+    assert_eq!(
+        integrity.to_string(),
+        "sha256-XN1Z6TwYperic6OzHKsyG2cU4pCISpTiyrg8Y8tWyZA="
+    );
+}
+
+#[test]
+fn test_archive_rejects_garbage() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut archive = Vec::new();
+    write_str(&mut archive, "nar-archive-1").unwrap();
+    assert!(restore(archive.as_slice(), dir.path().join("out")).is_err());
+
+    let mut archive = Vec::new();
+    write_str(&mut archive, MAGIC).unwrap();
+    for token in ["(", "type", "directory", "entry", "(", "name", "..", "node"] {
+        write_str(&mut archive, token).unwrap();
+    }
+    assert!(restore(archive.as_slice(), dir.path().join("out2")).is_err());
+}
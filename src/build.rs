@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::io::Write;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::{fs, io};
@@ -11,13 +12,15 @@ use futures::stream::futures_unordered;
 use futures::TryStreamExt;
 use indicatif::{MultiProgress, ProgressBar};
 use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, instrument, span, trace, Level};
 
+use crate::daemon::{Output, Request};
 use crate::eval::{RefToUnit, UnitRef};
 use crate::schema_eval::{Build, Unit};
 use crate::*;
 
-#[derive(Debug, clap::Args)]
+#[derive(Debug, Clone, clap::Args, Serialize, Deserialize)]
 /// Build a package
 pub struct Args {
     /// Unitref to build
@@ -38,14 +41,44 @@ pub struct Args {
     /// Maximum number of concurrent Package build jobs. Default: nproc
     #[arg(long = "jobs", short = 'j')]
     max_jobs: Option<usize>,
+
+    /// Symlink pointing to the result, which keeps it alive as a GC root
+    #[arg(long, short = 'o', default_value = "result")]
+    out_link: PathBuf,
+
+    /// Don't create a symlink to the result
+    #[arg(long, conflicts_with = "out_link")]
+    no_out_link: bool,
 }
 
 impl crate::Main for Args {
     fn main(&self) -> Result<()> {
+        if let Some(client) = crate::daemon::Client::connect()? {
+            // The daemon creates the out link, as it doesn't share the working directory
+            let args = Self {
+                out_link: std::path::absolute(&self.out_link)?,
+                ..self.clone()
+            };
+            client.request(Request::Build(args))?;
+            return Ok(());
+        }
+
         tokio::runtime::Runtime::new()?.block_on(self._main())
     }
 }
 
+impl Args {
+    /// Evaluate the unitref for another user, see [UnitRef::ref_to_unit_sandboxed]
+    pub fn unit_sandboxed(&self, reader: nix::unistd::Uid) -> Result<Unit> {
+        self.unit_ref.ref_to_unit_sandboxed(reader)
+    }
+
+    /// Whether building removes paths that are already in the store
+    pub fn removes_paths(&self) -> bool {
+        self.rebuild || self.rebuild_all
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum BuildTask {
     Waiting,
@@ -57,12 +90,40 @@ const MAX_BUILD_ITERATIONS: u32 = 1000;
 
 impl Args {
     async fn _main(&self) -> Result<()> {
+        let bars = MultiProgress::new();
         let root_node = self.unit_ref.ref_to_unit()?;
+        self.run(root_node, &bars, &mut |line| Ok(bars.println(line)?), None)
+            .await?;
+        Ok(())
+    }
+
+    /// Build `root_node`, evaluated from the unitref, and its dependencies, sending a line to
+    /// `report` for each one that finishes. The out link is created before the store is unlocked,
+    /// so that the result is rooted all along, and belongs to `link_owner` if it's built for
+    /// another user, see [crate::gc::create_out_link_as].
+    pub async fn run(
+        &self,
+        root_node: Unit,
+        bars: &MultiProgress,
+        report: Output<'_>,
+        link_owner: Option<nix::unistd::Uid>,
+    ) -> Result<Unit> {
+        let _lock = crate::lock::StoreLock::shared()?;
         let (dag, _) = eval::dag(root_node.clone())?;
         let dag: &'static mut _ = Box::leak(Box::new(dag));
 
         let db_conn = Arc::new(Mutex::new(crate::db::DbConnection::new()?));
 
+        // Garbage can be collected during the build, by this or another build that is low on disk
+        // space, which must not take what it already built
+        let _temp_roots = crate::gc::TempRoots::new(
+            dag.raw_nodes()
+                .iter()
+                .map(|node| node.weight.result().store_path()),
+        )?;
+        let guard = crate::disk::DiskGuard::from_config();
+        let mut space_checks = tokio::time::interval(crate::disk::CHECK_INTERVAL);
+
         let mut build_tasks: HashMap<&Unit, BuildTask> = HashMap::new();
         let mut futs = futures_unordered::FuturesUnordered::new();
 
@@ -70,22 +131,20 @@ impl Args {
 
         build_tasks.insert(&root_node, BuildTask::Waiting);
 
-        let bars = MultiProgress::new();
-
-        let max_jobs = self.max_jobs.unwrap_or_else(|| num_cpus::get());
+        let max_jobs = self.max_jobs.unwrap_or_else(num_cpus::get);
         trace!(?max_jobs);
 
-        while !build_tasks.iter().all(|(_, task)| match task {
-            BuildTask::Finished => true,
-            _ => false,
-        }) {
+        while !build_tasks
+            .iter()
+            .all(|(_, task)| matches!(task, BuildTask::Finished))
+        {
             for index in dag.graph().node_indices() {
                 // Avoid blowing up
                 ensure!(
                     sentry <= MAX_BUILD_ITERATIONS,
                     "Build sentry reached, something might have gone wrong!"
                 );
-                sentry = sentry + 1;
+                sentry += 1;
 
                 let unit = &dag[index];
 
@@ -99,11 +158,8 @@ impl Args {
                     _ => continue,
                 };
 
-                let all_deps_built = dag.children(index).iter(&dag).all(|(_, dep_index)| {
-                    match &build_tasks.get(&dag[dep_index]) {
-                        Some(BuildTask::Finished) => true,
-                        _ => false,
-                    }
+                let all_deps_built = dag.children(index).iter(dag).all(|(_, dep_index)| {
+                    matches!(build_tasks.get(&dag[dep_index]), Some(BuildTask::Finished))
                 });
 
                 let number_packages_building = build_tasks
@@ -114,10 +170,24 @@ impl Args {
 
                 let can_add_to_tasks = match unit {
                     Unit::PackageUnit(_) => number_packages_building < max_jobs,
-                    Unit::FetchUnit(_) => true,
+                    Unit::FetchUnit(_) | Unit::EnvUnit(_) => true,
                 };
 
-                let task_status = if all_deps_built && can_add_to_tasks {
+                // Paths that are already built don't need any space
+                let has_space = match &guard {
+                    Some(guard) if all_deps_built && can_add_to_tasks => {
+                        let present = !self.rebuild
+                            && !self.rebuild_all
+                            && db_conn
+                                .lock()
+                                .unwrap()
+                                .is_db_path(unit.result().store_path())?;
+                        present || guard.allow_job(&db_conn, !futs.is_empty())?
+                    }
+                    _ => true,
+                };
+
+                let task_status = if all_deps_built && can_add_to_tasks && has_space {
                     let _db_conn = db_conn.clone();
                     // let unit = unit.clone();
                     let rebuild = match (self, &unit) {
@@ -147,6 +217,7 @@ impl Args {
                     ?number_packages_building,
                     ?can_add_to_tasks,
                     ?all_deps_built,
+                    ?has_space,
                     ?task_status,
                     ?sentry
                 );
@@ -154,21 +225,29 @@ impl Args {
                 build_tasks.insert(unit, task_status);
             }
 
-            while let Some((unit, output)) = futs.try_next().await? {
+            loop {
+                let next = tokio::select! {
+                    next = futs.try_next() => next?,
+                    _ = space_checks.tick(), if guard.is_some() => {
+                        guard.as_ref().unwrap().collect_if_low(&db_conn)?;
+                        continue;
+                    }
+                };
+                let Some((unit, output)) = next else {
+                    break;
+                };
                 debug!(?unit, ?output, "Task finished");
+                let layout = crate::layout::get();
 
-                output
+                let outcome = output
                     .suggestion(format!(
                         "Check the unit definition at {}",
                         unit.result().eval_path().to_string_lossy()
                     ))
                     .suggestion(format!(
-                        "Build logs available at /miq/log/{}.log",
+                        "Build logs available at {}/{}.log",
+                        layout.log_dir().to_string_lossy(),
                         unit.result().as_str()
-                    ))
-                    .suggestion(format!(
-                        "Intermetidate results at {}",
-                        unit.result().store_path().to_string_lossy()
                     ))?;
 
                 let t = build_tasks.get_mut(&unit).unwrap();
@@ -176,19 +255,91 @@ impl Args {
 
                 let u = format!("{unit:?}");
                 let msg = format!(
-                    "{} <- {}",
+                    "{} <- {} {}",
                     unit.result().store_path().to_string_lossy().bright_green(),
-                    &u.bright_black()
+                    &u.bright_black(),
+                    format!("({})", outcome).bright_black()
                 );
-                bars.println(msg)?;
+                report(msg)?;
             }
 
             trace!(?build_tasks);
         }
-        Ok(())
+
+        if !self.no_out_link {
+            let path = crate::layout::get().physical(root_node.result().store_path());
+            match link_owner {
+                Some(owner) => crate::gc::create_out_link_as(&self.out_link, &path, owner)?,
+                None => crate::gc::create_out_link(&self.out_link, path)?,
+            }
+        }
+
+        Ok(root_node)
     }
 }
 
+/// Build `unit`, evaluated from `unit_ref`, with the default options and without creating an out
+/// link
+pub fn build_unit_ref(unit_ref: UnitRef, unit: Unit) -> Result<Unit> {
+    let args = Args {
+        unit_ref,
+        quiet: false,
+        rebuild: false,
+        rebuild_all: false,
+        max_jobs: None,
+        out_link: PathBuf::new(),
+        no_out_link: true,
+    };
+
+    let bars = MultiProgress::new();
+    tokio::runtime::Runtime::new()?.block_on(args.run(
+        unit,
+        &bars,
+        &mut |line| Ok(bars.println(line)?),
+        None,
+    ))
+}
+
+/// Prefix of temporary entries in the store. They are removed by [clean_store] if the process that
+/// made them dies before moving them into place.
+pub const TEMP_PREFIX: &str = ".tmp-";
+
+/// A temporary directory in the store, to produce a path in before renaming it into place
+pub fn store_tempdir(label: &str) -> Result<tempfile::TempDir> {
+    let dir = tempfile::Builder::new()
+        .prefix(&format!("{}{}-", TEMP_PREFIX, label))
+        .tempdir_in(crate::layout::get().store_dir())?;
+    Ok(dir)
+}
+
+/// Replace `path` with `contents`, through a temporary sibling, so that readers never see a
+/// partially written file
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap();
+    let mut temp = tempfile::Builder::new()
+        .prefix(TEMP_PREFIX)
+        .tempfile_in(dir)?;
+    temp.write_all(contents)?;
+    temp.persist(path)?;
+    Ok(())
+}
+
+/// Remove the temporary entries that an interrupted process left in the store. Paths that are not
+/// registered are kept, as they might have been put there by hand or belong to another database:
+/// `miq store verify --all` reports them.
+pub fn clean_store() -> Result<()> {
+    for entry in fs::read_dir(crate::layout::get().store_dir())? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default();
+        if name.as_encoded_bytes().starts_with(TEMP_PREFIX.as_bytes()) {
+            debug!(?path, "Removing temporary entry");
+            clean_path(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
 #[instrument(ret, err, level = "trace")]
 pub fn clean_path<P: AsRef<Path> + Debug>(path: P) -> Result<()> {
     match fs::metadata(&path) {
@@ -223,3 +374,18 @@ pub fn check_path<P: AsRef<Path> + Debug>(path: P) -> Result<()> {
         Err(e) => bail!(e),
     }
 }
+
+#[test]
+fn test_clean_store() {
+    let _store = crate::layout::test_store();
+    let store_dir = crate::layout::get().store_dir();
+    let temp = store_dir.join(format!("{}interrupted", TEMP_PREFIX));
+    let by_hand = store_dir.join("by-hand-5s6j4qv2xj9qjyj3m4x5fxwb51j5n7rx");
+    fs::create_dir(&temp).unwrap();
+    fs::write(&by_hand, "").unwrap();
+
+    clean_store().unwrap();
+    assert!(!temp.exists());
+    assert!(by_hand.exists());
+    fs::remove_file(&by_hand).unwrap();
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fs::DirEntry;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use color_eyre::eyre::ensure;
+use color_eyre::Help;
+use indicatif::ProgressBar;
+use tracing::debug;
+
+use crate::db::DbConnection;
+use crate::lock::PathLock;
+use crate::schema_eval::{Build, BuildOutcome, Env};
+use crate::*;
+
+#[async_trait]
+impl Build for Env {
+    #[tracing::instrument(skip(conn), ret, err, level = "debug")]
+    async fn build(
+        &self,
+        rebuild: bool,
+        conn: &Mutex<DbConnection>,
+        pb: ProgressBar,
+    ) -> Result<BuildOutcome> {
+        let path = self.result.store_path();
+        let path = path.as_path();
+
+        if !rebuild && conn.lock().unwrap().is_db_path(path)? {
+            return Ok(BuildOutcome::Present);
+        }
+
+        // Once the lock is taken, the path is present if another process was producing it
+        let _lock = PathLock::lock_async(&self.result, &pb).await?;
+        if conn.lock().unwrap().is_db_path(path)? {
+            if rebuild {
+                conn.lock().unwrap().remove_unchecked(path)?;
+            } else {
+                return Ok(BuildOutcome::Present);
+            }
+        } else if crate::cache::substitute(&self.result, conn, &pb).await? {
+            pb.finish_and_clear();
+            return Ok(BuildOutcome::Substituted);
+        }
+
+        crate::build::clean_path(crate::layout::get().physical(path))?;
+        let temp = crate::build::store_tempdir("env")?;
+        let out = temp.path().join("out");
+        std::fs::create_dir(&out)?;
+
+        pb.set_message(self.name.clone());
+        let mut forest = SymlinkForest::new(&out, self.ignore_collisions);
+        for input in &self.paths {
+            let input = input.store_path();
+            ensure!(
+                crate::layout::get().physical(&input).is_dir(),
+                "{:?} is not a directory, so it can't be merged into {}",
+                input.as_path(),
+                self.name
+            );
+            forest
+                .add(&input, Path::new(""))
+                .suggestion("Set ignore_collisions = true to keep the first one")?;
+        }
+
+        let refs = self
+            .paths
+            .iter()
+            .map(|input| input.store_path().to_string_lossy().into_owned())
+            .collect();
+        conn.lock().unwrap().add_from(&out, path, refs)?;
+        pb.finish_and_clear();
+        Ok(BuildOutcome::Built)
+    }
+}
+
+/// A tree of directories with symlinks to the files of some store paths, like the output of an
+/// [Env] or a profile generation
+pub struct SymlinkForest {
+    root: PathBuf,
+    /// Where each entry of the tree comes from, to name both sides of a collision
+    sources: HashMap<PathBuf, PathBuf>,
+    ignore_collisions: bool,
+    /// Directories being merged, which symlinks aren't followed to, as they would loop
+    merging: Vec<PathBuf>,
+}
+
+impl SymlinkForest {
+    pub fn new<P: Into<PathBuf>>(root: P, ignore_collisions: bool) -> Self {
+        Self {
+            root: root.into(),
+            sources: HashMap::new(),
+            ignore_collisions,
+            merging: Vec::new(),
+        }
+    }
+
+    /// Merge the directory at the store path `logical` into `dest`, relative to the root
+    pub fn add(&mut self, logical: &Path, dest: &Path) -> Result<()> {
+        let physical = crate::layout::get().physical(logical);
+        self.add_dir(&physical, logical, dest)
+    }
+
+    fn add_dir(&mut self, physical: &Path, logical: &Path, dest: &Path) -> Result<()> {
+        std::fs::create_dir_all(self.root.join(dest))?;
+        self.merging.push(physical.canonicalize()?);
+
+        for entry in std::fs::read_dir(physical)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let source = logical.join(&name);
+            let rel = dest.join(&name);
+            let dir = self.dir_to_merge(physical, &entry)?;
+            let is_dir = dir.is_some();
+
+            match std::fs::symlink_metadata(self.root.join(&rel)) {
+                Ok(meta) if is_dir && meta.is_dir() => {}
+                Ok(_) => {
+                    let existing = &self.sources[&rel];
+                    if !self.ignore_collisions {
+                        bail!(
+                            "{:?} is provided by both {:?} and {:?}",
+                            rel,
+                            existing,
+                            source
+                        );
+                    }
+                    debug!(?rel, ?existing, ignored = ?source, "Ignoring collision");
+                    continue;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    self.sources.insert(rel.clone(), source.clone());
+                }
+                Err(err) => bail!(err),
+            }
+
+            match dir {
+                Some(dir) => self.add_dir(&dir, &source, &rel)?,
+                None => std::os::unix::fs::symlink(&source, self.root.join(&rel))?,
+            }
+        }
+
+        self.merging.pop();
+        Ok(())
+    }
+
+    /// Where to read the entry of `physical` from if it's merged as a directory. Symlinks to
+    /// directories are, like `lib -> lib64`, so that they don't collide with the real ones.
+    fn dir_to_merge(&self, physical: &Path, entry: &DirEntry) -> Result<Option<PathBuf>> {
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            return Ok(Some(entry.path()));
+        }
+        if !file_type.is_symlink() {
+            return Ok(None);
+        }
+
+        // Store paths link to each other with their logical paths
+        let target = physical.join(std::fs::read_link(entry.path())?);
+        match crate::layout::get().physical(target).canonicalize() {
+            Ok(target) if target.is_dir() && !self.merging.contains(&target) => Ok(Some(target)),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[test]
+fn test_symlink_forest() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = dir.path().join("first");
+    let second = dir.path().join("second");
+    let dest = dir.path().join("dest");
+    std::fs::create_dir_all(first.join("share/man")).unwrap();
+    std::fs::create_dir_all(second.join("share/man")).unwrap();
+    std::fs::write(first.join("share/man/foo.1"), "foo").unwrap();
+    std::fs::write(second.join("share/man/bar.1"), "bar").unwrap();
+
+    let mut forest = SymlinkForest::new(&dest, false);
+    forest.add(&first, Path::new("")).unwrap();
+    forest.add(&second, Path::new("")).unwrap();
+    assert_eq!(
+        std::fs::read_link(dest.join("share/man/bar.1")).unwrap(),
+        second.join("share/man/bar.1")
+    );
+    assert_eq!(
+        std::fs::read_to_string(dest.join("share/man/foo.1")).unwrap(),
+        "foo"
+    );
+
+    std::fs::write(second.join("share/man/foo.1"), "other").unwrap();
+    let mut forest = SymlinkForest::new(dir.path().join("colliding"), false);
+    forest.add(&first, Path::new("")).unwrap();
+    let err = forest.add(&second, Path::new("")).unwrap_err().to_string();
+    assert!(err.contains("first/share/man/foo.1"), "{}", err);
+    assert!(err.contains("second/share/man/foo.1"), "{}", err);
+
+    let mut forest = SymlinkForest::new(dir.path().join("ignoring"), true);
+    forest.add(&first, Path::new("")).unwrap();
+    forest.add(&second, Path::new("")).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("ignoring/share/man/foo.1")).unwrap(),
+        "foo"
+    );
+    // A symlink to a directory is merged like it
+    std::fs::remove_file(second.join("share/man/foo.1")).unwrap();
+    std::fs::create_dir_all(first.join("lib")).unwrap();
+    std::fs::write(first.join("lib/libfoo.so"), "foo").unwrap();
+    std::fs::create_dir_all(second.join("lib64")).unwrap();
+    std::fs::write(second.join("lib64/libbar.so"), "bar").unwrap();
+    std::os::unix::fs::symlink("lib64", second.join("lib")).unwrap();
+    std::os::unix::fs::symlink("..", second.join("lib64/loop")).unwrap();
+    let mut forest = SymlinkForest::new(dir.path().join("libs"), false);
+    forest.add(&first, Path::new("")).unwrap();
+    forest.add(&second, Path::new("")).unwrap();
+    let libs = dir.path().join("libs/lib");
+    assert_eq!(
+        std::fs::read_link(libs.join("libfoo.so")).unwrap(),
+        first.join("lib/libfoo.so")
+    );
+    assert_eq!(
+        std::fs::read_link(libs.join("libbar.so")).unwrap(),
+        second.join("lib/libbar.so")
+    );
+    // Unless it loops
+    assert_eq!(
+        std::fs::read_link(libs.join("loop")).unwrap(),
+        second.join("lib/loop")
+    );
+}
+
+#[test]
+fn test_env_build() {
+    use crate::eval::{RefToUnit, UnitRef};
+    use crate::schema_eval::Unit;
+    use std::str::FromStr;
+
+    let _store = crate::layout::test_store();
+    let layout = crate::layout::get();
+    let file = layout.root().join("env.lua");
+    std::fs::write(
+        &file,
+        r#"
+        local miq = require "miq"
+        local first = miq.fetch { name = "env-first", url = "https://example.com/first" }
+        local second = miq.fetch { name = "env-second", url = "https://example.com/second" }
+        return { env = miq.env { name = "env-test", paths = { first, second, first } } }
+        "#,
+    )
+    .unwrap();
+    let unit_ref = UnitRef::from_str(&format!("{}#env", file.display())).unwrap();
+    let Unit::EnvUnit(env) = unit_ref.ref_to_unit().unwrap() else {
+        panic!("Not an env");
+    };
+    assert_eq!(env.paths.len(), 2);
+
+    // The inputs are in the store already, so they are not fetched
+    let mut conn = DbConnection::new().unwrap();
+    for (input, name) in env.paths.iter().zip(["first", "second"]) {
+        let bin = layout.physical(input.store_path()).join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        std::fs::write(bin.join(name), name).unwrap();
+        conn.add(input.store_path()).unwrap();
+    }
+
+    let unit = crate::build::build_unit_ref(unit_ref, Unit::EnvUnit(env.clone())).unwrap();
+    assert_eq!(unit, Unit::EnvUnit(env.clone()));
+
+    let path = env.result.store_path();
+    assert!(conn.is_db_path(&path).unwrap());
+    let mut inputs: Vec<String> = env
+        .paths
+        .iter()
+        .map(|input| input.store_path().to_string_lossy().into_owned())
+        .collect();
+    inputs.sort();
+    let mut references = conn.references(&path).unwrap();
+    references.sort();
+    assert_eq!(references, inputs);
+    assert_eq!(
+        std::fs::read_link(layout.physical(&path).join("bin/second")).unwrap(),
+        env.paths[1].store_path().join("bin/second")
+    );
+}
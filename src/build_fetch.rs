@@ -1,15 +1,24 @@
 use std::fs::Permissions;
 use std::os::unix::prelude::PermissionsExt;
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::Mutex;
 
 use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use color_eyre::eyre::Context;
+use color_eyre::{Help, Report};
+use futures::{Stream, TryStreamExt};
 use futures_util::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::debug;
+use url::Url;
 
 use crate::db::DbConnection;
-use crate::schema_eval::{Build, Fetch};
+use crate::hash::{Integrity, IntegrityHasher};
+use crate::lock::PathLock;
+use crate::schema_eval::{Build, BuildOutcome, Fetch};
 use crate::*;
 
 #[async_trait]
@@ -20,49 +29,52 @@ impl Build for Fetch {
         rebuild: bool,
         conn: &Mutex<DbConnection>,
         pb: ProgressBar,
-    ) -> Result<()> {
+    ) -> Result<BuildOutcome> {
         let path = self.result.store_path();
         let path = path.as_path();
 
-        if conn.lock().unwrap().is_db_path(&path)? {
+        if !rebuild && conn.lock().unwrap().is_db_path(path)? {
+            return Ok(BuildOutcome::Present);
+        }
+
+        // Once the lock is taken, the path is present if another process was producing it
+        let _lock = PathLock::lock_async(&self.result, &pb).await?;
+        if conn.lock().unwrap().is_db_path(path)? {
             if rebuild {
-                conn.lock().unwrap().remove(&path)?;
+                conn.lock().unwrap().remove_unchecked(path)?;
             } else {
-                return Ok(());
+                return Ok(BuildOutcome::Present);
             }
+        } else if crate::cache::substitute(&self.result, conn, &pb).await? {
+            pb.finish_and_clear();
+            return Ok(BuildOutcome::Substituted);
         }
 
-        crate::build::clean_path(path)?;
+        crate::build::clean_path(crate::layout::get().physical(path))?;
+        let temp = crate::build::store_tempdir("fetch")?;
+        let out = temp.path().join("out");
 
-        let client = reqwest::Client::new();
-        let response = client.get(&self.url).send().await?;
+        pb.set_message(self.name.clone());
+        let got = download(&self.url, &out, &pb).await?;
 
-        let status = response.status();
-        if !status.is_success() {
-            bail!(status);
-        }
-
-        if let Some(total_length) = response.content_length() {
-            pb.set_length(total_length);
-            pb.set_message(self.name.clone());
-            pb.set_style(ProgressStyle::with_template(
-                "{msg:.blue}>> {percent}% {wide_bar}",
-            )?);
-        };
-
-        let out_file = tokio::fs::File::create(path).await?;
-
-        let pb_writer = pb.wrap_async_write(out_file);
-        let mut buf_writer = tokio::io::BufWriter::new(pb_writer);
-        let mut stream = response.bytes_stream();
-
-        while let Some(item) = stream.next().await {
-            let item = item?;
-            tokio::io::copy(&mut item.as_ref(), &mut buf_writer).await?;
+        match self.integrity {
+            Some(expected) if expected == got => {
+                debug!(%got, "Integrity check passed");
+            }
+            Some(expected) => {
+                bail!(
+                    "Hash mismatch for {}: expected {}, got {}",
+                    self.url,
+                    expected,
+                    got
+                );
+            }
+            None => {
+                return Err(eyre!("No hash given for {}, got {}", self.url, got)
+                    .suggestion(format!("Add hash = \"{}\" to the fetch", got)));
+            }
         }
 
-        buf_writer.flush().await?;
-
         let perm = if self.executable {
             debug!("Setting as executable exec bit");
             Permissions::from_mode(0o555)
@@ -70,11 +82,80 @@ impl Build for Fetch {
             Permissions::from_mode(0o444)
         };
 
-        tokio::fs::set_permissions(path, perm).await?;
+        tokio::fs::set_permissions(&out, perm).await?;
 
-
-        conn.lock().unwrap().add(&path)?;
+        conn.lock().unwrap().add_from(&out, path, Vec::new())?;
         pb.finish_and_clear();
-        Ok(())
+        Ok(BuildOutcome::Built)
+    }
+}
+
+pub(crate) type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Open `url` for reading, returning its length if known. `file://` URLs are read from disk.
+pub(crate) async fn open_url(url: &str) -> Result<(Option<u64>, ByteStream)> {
+    let parsed = Url::parse(url).wrap_err(format!("Parsing URL {:?}", url))?;
+
+    if parsed.scheme() == "file" {
+        let src = parsed
+            .to_file_path()
+            .map_err(|_| eyre!("Not a local file: {}", url))?;
+        let file = tokio::fs::File::open(&src)
+            .await
+            .wrap_err(format!("Opening {:?}", src))?;
+        let len = file.metadata().await?.len();
+
+        let stream = futures::stream::try_unfold(file, |mut file| async move {
+            let mut buf = BytesMut::with_capacity(FILE_CHUNK_SIZE);
+            match file.read_buf(&mut buf).await? {
+                0 => Ok(None),
+                _ => Ok(Some((buf.freeze(), file))),
+            }
+        });
+
+        return Ok((Some(len), Box::pin(stream)));
+    }
+
+    let client = reqwest::Client::new();
+    let response = client.get(url).send().await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        bail!(status);
+    }
+
+    let len = response.content_length();
+    let stream = response.bytes_stream().map_err(Report::from);
+
+    Ok((len, Box::pin(stream)))
+}
+
+/// Stream `url` into `path`, hashing the bytes as they arrive
+pub async fn download(url: &str, path: &Path, pb: &ProgressBar) -> Result<Integrity> {
+    let (len, mut stream) = open_url(url).await?;
+
+    if let Some(total_length) = len {
+        pb.set_length(total_length);
+        pb.set_style(ProgressStyle::with_template(
+            "{msg:.blue}>> {percent}% {wide_bar}",
+        )?);
+    };
+
+    let out_file = tokio::fs::File::create(path).await?;
+
+    let pb_writer = pb.wrap_async_write(out_file);
+    let mut buf_writer = tokio::io::BufWriter::new(pb_writer);
+    let mut hasher = IntegrityHasher::default();
+
+    while let Some(item) = stream.next().await {
+        let item = item?;
+        hasher.update(&item);
+        tokio::io::copy(&mut item.as_ref(), &mut buf_writer).await?;
     }
+
+    buf_writer.flush().await?;
+
+    Ok(hasher.finish())
 }
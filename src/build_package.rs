@@ -1,7 +1,7 @@
 use std::io::Write;
 use std::ops::Deref;
 use std::os::fd::AsRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::sync::Mutex;
 use std::time::Duration;
@@ -19,8 +19,9 @@ use tokio_process_stream::{Item, ProcessLineStream};
 use tracing::{debug, span, trace, Level};
 
 use crate::db::DbConnection;
+use crate::lock::PathLock;
 use crate::mem_app::MemApp;
-use crate::schema_eval::{Build, Package};
+use crate::schema_eval::{Build, BuildOutcome, Package};
 use crate::*;
 
 const BUILD_SCRIPT_LOC: &str = "/build-script";
@@ -33,24 +34,60 @@ impl Build for Package {
         rebuild: bool,
         conn: &Mutex<DbConnection>,
         pb: ProgressBar,
-    ) -> Result<()> {
+    ) -> Result<BuildOutcome> {
         let path = self.result.store_path();
         let path = path.as_path();
         let _path_str = path.to_str().unwrap();
 
-        if conn.lock().unwrap().is_db_path(&path)? {
+        if !rebuild && conn.lock().unwrap().is_db_path(path)? {
+            return Ok(BuildOutcome::Present);
+        }
+
+        // Once the lock is taken, the path is present if another process was producing it
+        let _lock = PathLock::lock_async(&self.result, &pb).await?;
+        if conn.lock().unwrap().is_db_path(path)? {
             if rebuild {
-                conn.lock().unwrap().remove(&path)?;
+                conn.lock().unwrap().remove_unchecked(path)?;
             } else {
-                return Ok(());
+                return Ok(BuildOutcome::Present);
             }
+        } else if crate::cache::substitute(&self.result, conn, &pb).await? {
+            pb.finish_and_clear();
+            return Ok(BuildOutcome::Substituted);
         }
 
         pb.set_message(self.name.clone());
         pb.set_style(ProgressStyle::with_template("{msg:.blue}>> {spinner}")?);
         pb.enable_steady_tick(Duration::from_millis(500));
 
-        crate::build::clean_path(&path)?;
+        crate::build::clean_path(crate::layout::get().physical(path))?;
+        // The sandbox gets a store of its own in a temporary directory, with the paths of the
+        // dependencies mounted into it. The script writes the output at its logical path there,
+        // which is only moved into the real store once it's registered.
+        let temp = crate::build::store_tempdir("build")?;
+        let store_view = temp.path().join("store");
+        std::fs::create_dir(&store_view)?;
+        let out_path = store_view.join(self.result.as_str());
+
+        let deps = eval::deps_closure(&self.deps)?;
+        let mut inputs = Vec::new();
+        for dep in &deps {
+            let physical = crate::layout::get().physical(dep.store_path());
+            let target = store_view.join(dep.as_str());
+            let meta = std::fs::symlink_metadata(&physical)
+                .wrap_err(format!("Reading the dependency {:?}", physical))?;
+            if meta.is_symlink() {
+                std::os::unix::fs::symlink(std::fs::read_link(&physical)?, &target)?;
+                continue;
+            }
+            if meta.is_dir() {
+                std::fs::create_dir(&target)?;
+            } else {
+                std::fs::File::create(&target)?;
+            }
+            inputs.push(physical);
+        }
+
         let _build_dir = tempfile::tempdir()?;
         let build_path = _build_dir.path().to_owned();
 
@@ -84,6 +121,7 @@ impl Build for Package {
         cmd.stderr(Stdio::piped());
 
         let _self = self.clone();
+        let _store_view = store_view.clone();
         unsafe {
             cmd.pre_exec(move || {
                 let pid = Pid::this();
@@ -107,13 +145,13 @@ impl Build for Package {
                 )?;
 
                 let mut uid_map = std::fs::File::create(format!("/proc/{}/uid_map", pid))?;
-                uid_map.write_all(&uid_map_contents.as_bytes())?;
+                uid_map.write_all(uid_map_contents.as_bytes())?;
 
                 let mut setgroups = std::fs::File::create(format!("/proc/{}/setgroups", pid))?;
                 setgroups.write_all("deny".as_bytes())?;
 
                 let mut gid_map = std::fs::File::create(format!("/proc/{}/gid_map", pid))?;
-                gid_map.write_all(&gid_map_contents.as_bytes())?;
+                gid_map.write_all(gid_map_contents.as_bytes())?;
 
                 let bash = mem_app::BASH
                     .as_ref()
@@ -123,7 +161,14 @@ impl Build for Package {
                     .as_ref()
                     .map_err(|e| Into::<io::Error>::into(*e))?;
 
-                _self.sandbox_setup(bash, busybox, &sandbox_path, &build_path)?;
+                _self.sandbox_setup(
+                    bash,
+                    busybox,
+                    &sandbox_path,
+                    &build_path,
+                    &_store_view,
+                    &inputs,
+                )?;
 
                 debug!("pre_exec done");
                 Ok(())
@@ -132,18 +177,20 @@ impl Build for Package {
 
         let child = cmd.spawn()?;
 
-        let log_file_path = format!("/miq/log/{}.log", self.result.deref());
+        let log_file_path = crate::layout::get()
+            .log_dir()
+            .join(format!("{}.log", self.result.deref()));
         let log_file = tokio::fs::File::create(&log_file_path).await?;
         let mut log_writer = tokio::io::BufWriter::new(log_file);
 
-        let mut procstream = ProcessLineStream::try_from(child)?;
+        let mut procstream = ProcessLineStream::from(child);
         while let Some(item) = procstream.next().await {
             use owo_colors::OwoColorize;
             let msg = match item {
                 Item::Stdout(line) | Item::Stderr(line) => line,
                 Item::Done(Ok(exit)) => {
                     if exit.success() {
-                        format!("miq: exit ok")
+                        "miq: exit ok".to_string()
                     } else {
                         bail!(eyre!("Exit not successful").wrap_err(exit));
                     }
@@ -157,15 +204,24 @@ impl Build for Package {
             pb.tick();
         }
 
-        match path.try_exists().wrap_err("Failed to produce an output") {
-            Ok(true) => {}
-            Ok(false) => bail!("Output path doesn't exist: {:?}", path),
-            Err(e) => bail!(e),
+        let written = match std::fs::symlink_metadata(&out_path) {
+            Ok(meta) if meta.is_dir() => std::fs::read_dir(&out_path)?.next().is_some(),
+            Ok(_) => true,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => bail!(err),
+        };
+        if !written {
+            bail!("The build script didn't write anything to $miq_out");
         }
 
-        conn.lock().unwrap().add(&path)?;
+        let references = crate::refscan::scan_references(&out_path, &deps)?
+            .iter()
+            .map(|r| r.store_path().as_path().to_str().unwrap().to_owned())
+            .collect();
+
+        conn.lock().unwrap().add_from(&out_path, path, references)?;
         pb.finish_and_clear();
-        Ok(())
+        Ok(BuildOutcome::Built)
     }
 }
 
@@ -179,6 +235,8 @@ impl Package {
         busybox: &MemApp,
         sandbox_path: &Path,
         build_path: &Path,
+        store_view: &Path,
+        inputs: &[PathBuf],
     ) -> nix::Result<()> {
         let uid = Uid::effective();
         let gid = Gid::effective();
@@ -194,7 +252,7 @@ impl Package {
         )
         .unwrap();
 
-        for element in ["dev", "etc", "run", "tmp", "var", "sys", "miq", "proc"] {
+        for element in ["dev", "etc", "run", "tmp", "var", "sys", "proc"] {
             let new_path = sandbox_path.join(element);
             std::fs::create_dir(&new_path).unwrap();
             mount(
@@ -206,6 +264,40 @@ impl Package {
             )?;
         }
 
+        // Builds see the store at its logical location, wherever its root is
+        {
+            let new_path = sandbox_path.join("miq");
+            std::fs::create_dir(&new_path).unwrap();
+            mount(
+                Some(crate::layout::get().root()),
+                &new_path,
+                NONE_NIX,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                NONE_NIX,
+            )?;
+        }
+
+        // Over it, the store of the build, where only the dependencies are
+        {
+            let new_path = sandbox_path.join(crate::hash::HASH_STORE_DIR.trim_start_matches('/'));
+            mount(
+                Some(store_view),
+                &new_path,
+                NONE_NIX,
+                MsFlags::MS_BIND,
+                NONE_NIX,
+            )?;
+            for input in inputs {
+                mount(
+                    Some(input),
+                    &new_path.join(input.file_name().unwrap()),
+                    NONE_NIX,
+                    MsFlags::MS_BIND | MsFlags::MS_REC,
+                    NONE_NIX,
+                )?;
+            }
+        }
+
         {
             let new_path = sandbox_path.join("build");
             std::fs::create_dir(&new_path).unwrap();
@@ -233,7 +325,7 @@ impl Package {
                 bin_path.join("bash"),
             )
             .unwrap();
-            std::os::unix::fs::symlink(format!("/bin/bash"), bin_path.join("sh")).unwrap();
+            std::os::unix::fs::symlink("/bin/bash", bin_path.join("sh")).unwrap();
         }
 
         {
@@ -254,8 +346,7 @@ impl Package {
             .unwrap();
 
             for applet in crate::busybox::BUSYBOX_APPLETS {
-                std::os::unix::fs::symlink(format!("/usr/bin/busybox"), usr_path.join(applet))
-                    .unwrap();
+                std::os::unix::fs::symlink("/usr/bin/busybox", usr_path.join(applet)).unwrap();
             }
         }
 
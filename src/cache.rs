@@ -0,0 +1,458 @@
+//! Binary caches, which hold outputs built on another machine.
+//!
+//! A cache is a directory, read from disk with `file://` or served over HTTP, with this layout:
+//! - `info/<result>.json`: the [CacheInfo] of a path
+//! - `archive/<result>.zst`: its archive, see [crate::archive], compressed with zstd
+//! - `index.json`: the [CacheIndex] of every path in the cache
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use color_eyre::eyre::{bail, ensure, eyre, Context};
+use color_eyre::{Report, Result};
+use futures::TryStreamExt;
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::archive::HashingWriter;
+use crate::db::DbConnection;
+use crate::eval::MiqResult;
+use crate::export::{PathMetadata, Unpacked};
+use crate::hash::Integrity;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheInfo {
+    #[serde(flatten)]
+    pub path: PathMetadata,
+    /// Location of the compressed archive, relative to the root of the cache
+    pub url: String,
+    /// Hash of the compressed archive
+    pub file_hash: Integrity,
+    pub file_size: u64,
+    /// Contents of the eval file of the unit that produced the path, installed along with it if
+    /// its hash matches the signed metadata
+    #[serde(default)]
+    pub eval: Option<String>,
+}
+
+/// Summary of the paths in a cache, so that clients don't need to list `info`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CacheIndex {
+    pub paths: BTreeMap<String, IndexEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub archive_hash: Integrity,
+    pub archive_size: u64,
+    pub file_size: u64,
+}
+
+pub fn info_location(result: &MiqResult) -> String {
+    format!("info/{}.json", result.as_str())
+}
+
+pub fn archive_location(result: &MiqResult) -> String {
+    format!("archive/{}.zst", result.as_str())
+}
+
+const INDEX_LOCATION: &str = "index.json";
+
+/// Resolve `location` relative to the root of the cache at `base`
+pub fn cache_url(base: &Url, location: &str) -> Result<Url> {
+    let mut base = base.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    base.join(location)
+        .wrap_err(format!("Resolving {:?} in {}", location, base))
+}
+
+fn is_not_found(err: &Report) -> bool {
+    if let Some(status) = err.downcast_ref::<reqwest::StatusCode>() {
+        return *status == reqwest::StatusCode::NOT_FOUND;
+    }
+    err.chain().any(|e| {
+        e.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+    })
+}
+
+/// Read the whole contents of `url`, or `None` if it doesn't exist
+async fn read_url(url: &Url) -> Result<Option<Vec<u8>>> {
+    let (_, stream) = match crate::build_fetch::open_url(url.as_str()).await {
+        Ok(opened) => opened,
+        Err(err) if is_not_found(&err) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let contents = stream
+        .try_fold(Vec::new(), |mut acc, chunk| async move {
+            acc.extend_from_slice(&chunk);
+            Ok(acc)
+        })
+        .await?;
+
+    Ok(Some(contents))
+}
+
+async fn try_substitute(
+    base: &Url,
+    result: &MiqResult,
+    conn: &Mutex<DbConnection>,
+    pb: &ProgressBar,
+) -> Result<bool> {
+    let info_url = cache_url(base, &info_location(result))?;
+    let Some(info) = read_url(&info_url).await? else {
+        return Ok(false);
+    };
+    let info: CacheInfo =
+        serde_json::from_slice(&info).wrap_err(format!("Parsing {}", info_url))?;
+
+    let path = result.store_path();
+    ensure!(
+        path.as_path() == std::path::Path::new(&info.path.store_path),
+        "{} is for {:?}",
+        info_url,
+        info.path.store_path
+    );
+
+    crate::signing::check_trusted(&info.path)?;
+    let unit = match &info.eval {
+        Some(eval) => Some(crate::export::trusted_eval(&info.path, eval)?),
+        None => None,
+    };
+
+    for reference in &info.path.references {
+        if path.as_path() != std::path::Path::new(reference)
+            && !conn.lock().unwrap().is_db_path(reference)?
+        {
+            debug!(
+                ?reference,
+                "Reference is not in the store, not substituting"
+            );
+            return Ok(false);
+        }
+    }
+
+    let archive_url = cache_url(base, &info.url)?;
+    let temp = tempfile::Builder::new()
+        .prefix(&format!("{}download-", crate::build::TEMP_PREFIX))
+        .tempfile_in(crate::layout::get().store_dir())?;
+    let got = crate::build_fetch::download(archive_url.as_str(), temp.path(), pb).await?;
+    ensure!(
+        got == info.file_hash,
+        "Hash mismatch for {}: expected {}, got {}",
+        archive_url,
+        info.file_hash,
+        got
+    );
+
+    let decoder = zstd::Decoder::new(std::fs::File::open(temp.path())?)?;
+    let unpacked = Unpacked::new(decoder, info.path)?;
+    crate::export::install(&mut conn.lock().unwrap(), &[unpacked])?;
+
+    // So that the path can be repaired, or its closure inspected, like if it was built here
+    if let Some(unit) = unit {
+        if !unit.result().eval_path().exists() {
+            unit.write_to_disk()?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Try to download the output of `result` from the configured substituters, returning whether
+/// it was found. A substituter that fails is skipped with a warning.
+pub async fn substitute(
+    result: &MiqResult,
+    conn: &Mutex<DbConnection>,
+    pb: &ProgressBar,
+) -> Result<bool> {
+    for base in &crate::config::get().substituters {
+        match try_substitute(base, result, conn, pb).await {
+            Ok(true) => {
+                info!(%base, ?result, "Substituted");
+                return Ok(true);
+            }
+            Ok(false) => debug!(%base, ?result, "Not in substituter"),
+            Err(err) => warn!(%base, ?err, "Substituter failed"),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Directory of a cache that can be written to
+pub fn local_cache_dir(base: &Url) -> Result<std::path::PathBuf> {
+    if base.scheme() != "file" {
+        bail!("Only file:// caches can be written to, got {}", base);
+    }
+    base.to_file_path()
+        .map_err(|_| eyre!("Not a local directory: {}", base))
+}
+
+/// Add a registered path to the cache at `dir`, returning false if it was already there. The
+/// info file is written last, so an interrupted copy doesn't leave a path that looks complete.
+pub fn write_path(dir: &Path, conn: &DbConnection, path: &str) -> Result<bool> {
+    let result =
+        MiqResult::from_store_path(path).ok_or_else(|| eyre!("{:?} is not a store path", path))?;
+
+    let info_path = dir.join(info_location(&result));
+    if info_path.exists() {
+        debug!(?path, "Already in the cache");
+        return Ok(false);
+    }
+
+    std::fs::create_dir_all(dir.join("info"))?;
+    std::fs::create_dir_all(dir.join("archive"))?;
+
+    let metadata = PathMetadata::read_signed(conn, path)?;
+    let archive = archive_location(&result);
+    let archive_path = dir.join(&archive);
+
+    let temp = tempfile::Builder::new()
+        .prefix(".tmp-")
+        .tempfile_in(archive_path.parent().unwrap())?;
+    let mut encoder = zstd::Encoder::new(HashingWriter::new(temp), 0)?;
+    metadata.dump(&mut encoder)?;
+    let (temp, file_hash, file_size) = encoder.finish()?.finish();
+    temp.persist(&archive_path)?;
+
+    let info = CacheInfo {
+        path: metadata,
+        url: archive,
+        file_hash,
+        file_size,
+        eval: crate::export::read_eval(path)?,
+    };
+    crate::build::write_atomic(&info_path, &serde_json::to_vec_pretty(&info)?)?;
+
+    Ok(true)
+}
+
+/// Regenerate the index of the cache at `dir` from its info files
+pub fn write_index(dir: &Path) -> Result<CacheIndex> {
+    let mut index = CacheIndex::default();
+
+    for entry in std::fs::read_dir(dir.join("info"))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.as_encoded_bytes().starts_with(b".") {
+            continue;
+        }
+
+        let text = std::fs::read(entry.path())?;
+        let info: CacheInfo =
+            serde_json::from_slice(&text).wrap_err(format!("Parsing {:?}", entry.path()))?;
+        index.paths.insert(
+            info.path.store_path,
+            IndexEntry {
+                archive_hash: info.path.archive_hash,
+                archive_size: info.path.archive_size,
+                file_size: info.file_size,
+            },
+        );
+    }
+
+    crate::build::write_atomic(
+        &dir.join(INDEX_LOCATION),
+        &serde_json::to_vec_pretty(&index)?,
+    )?;
+    Ok(index)
+}
+
+#[test]
+fn test_cache_url() {
+    let result = MiqResult::create("foo", &"foo");
+    for base in ["file:///srv/cache", "file:///srv/cache/"] {
+        let url = cache_url(&base.parse().unwrap(), &info_location(&result)).unwrap();
+        assert_eq!(
+            url.as_str(),
+            format!("file:///srv/cache/info/{}.json", result.as_str())
+        );
+    }
+
+    let base = "https://cache.example.com/miq".parse().unwrap();
+    let url = cache_url(&base, "archive/foo.zst").unwrap();
+    assert_eq!(
+        url.as_str(),
+        "https://cache.example.com/miq/archive/foo.zst"
+    );
+}
+
+/// Register a new path built from a unit, and write it to the cache at `dir`. Then remove it and
+/// its unit, so that it can be substituted.
+#[cfg(test)]
+fn cached_test_path(conn: &mut DbConnection, dir: &Path, name: &str, signed: bool) -> MiqResult {
+    let unit = crate::schema_eval::Unit::FetchUnit(crate::schema_eval::Fetch {
+        result: MiqResult::create(name, &name),
+        name: name.to_owned(),
+        ..Default::default()
+    });
+    unit.write_to_disk().unwrap();
+    let result = unit.result().clone();
+    let path = crate::db::add_test_path(conn, &result, name);
+    if signed {
+        let metadata = PathMetadata::read(conn, &path).unwrap();
+        let signature = crate::signing::test_key().sign(&metadata);
+        conn.add_signatures(&path, &[signature]).unwrap();
+    }
+
+    assert!(write_path(dir, conn, &path).unwrap());
+    conn.remove(&path).unwrap();
+    std::fs::remove_file(result.eval_path().as_path()).unwrap();
+    result
+}
+
+#[cfg(test)]
+fn test_substitute(
+    base: &Url,
+    result: &MiqResult,
+    conn: DbConnection,
+) -> (Result<bool>, DbConnection) {
+    let conn = Mutex::new(conn);
+    let substituted = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(try_substitute(base, result, &conn, &ProgressBar::hidden()));
+    (substituted, conn.into_inner().unwrap())
+}
+
+/// Serve the files of `dir` over HTTP on a local port, returning the URL of the root
+#[cfg(test)]
+fn serve_dir(dir: &Path) -> Url {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let dir = dir.to_owned();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut lines = BufReader::new(&stream).lines();
+            // Like "GET /info/foo.json HTTP/1.1", followed by the headers
+            let request = lines.next().unwrap().unwrap();
+            for line in lines.by_ref() {
+                if line.unwrap().is_empty() {
+                    break;
+                }
+            }
+
+            let location = request.split(' ').nth(1).unwrap_or("/");
+            let response = match std::fs::read(dir.join(location.trim_start_matches('/'))) {
+                Ok(body) => {
+                    let header = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    [header.into_bytes(), body].concat()
+                }
+                Err(_) => {
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_vec()
+                }
+            };
+            stream.write_all(&response).unwrap();
+        }
+    });
+
+    url.parse().unwrap()
+}
+
+#[test]
+fn test_substitute_file() {
+    let _store = crate::layout::test_store();
+    let cache = tempfile::tempdir().unwrap();
+    let base = Url::from_directory_path(cache.path()).unwrap();
+    let mut conn = DbConnection::new().unwrap();
+
+    let result = cached_test_path(&mut conn, cache.path(), "substitute-file", true);
+    let path = result.store_path();
+    let (substituted, mut conn) = test_substitute(&base, &result, conn);
+    assert!(substituted.unwrap());
+    assert!(conn.is_db_path(&path).unwrap());
+    assert_eq!(
+        std::fs::read_to_string(crate::layout::get().physical(&path)).unwrap(),
+        "substitute-file"
+    );
+    assert!(matches!(
+        crate::schema_eval::Unit::from_result(&result).unwrap(),
+        crate::schema_eval::Unit::FetchUnit(_)
+    ));
+
+    let missing = MiqResult::create("not-cached", &"not-cached");
+    let (substituted, _) = test_substitute(&base, &missing, conn);
+    assert!(!substituted.unwrap());
+}
+
+#[test]
+fn test_substitute_mismatch() {
+    let _store = crate::layout::test_store();
+    let cache = tempfile::tempdir().unwrap();
+    let base = Url::from_directory_path(cache.path()).unwrap();
+    let mut conn = DbConnection::new().unwrap();
+
+    let result = cached_test_path(&mut conn, cache.path(), "substitute-mismatch", true);
+    std::fs::write(cache.path().join(archive_location(&result)), "garbage").unwrap();
+
+    let (substituted, mut conn) = test_substitute(&base, &result, conn);
+    let err = substituted.unwrap_err().to_string();
+    assert!(err.contains("Hash mismatch"), "{}", err);
+    assert!(!conn.is_db_path(result.store_path()).unwrap());
+}
+
+#[test]
+fn test_substitute_unsigned() {
+    let _store = crate::layout::test_store();
+    let cache = tempfile::tempdir().unwrap();
+    let base = Url::from_directory_path(cache.path()).unwrap();
+    let mut conn = DbConnection::new().unwrap();
+
+    let result = cached_test_path(&mut conn, cache.path(), "substitute-unsigned", false);
+    let (substituted, mut conn) = test_substitute(&base, &result, conn);
+    let err = substituted.unwrap_err().to_string();
+    assert!(err.contains("not signed by a trusted key"), "{}", err);
+    assert!(!conn.is_db_path(result.store_path()).unwrap());
+}
+
+#[test]
+fn test_substitute_unsigned_eval() {
+    let _store = crate::layout::test_store();
+    let cache = tempfile::tempdir().unwrap();
+    let base = Url::from_directory_path(cache.path()).unwrap();
+    let mut conn = DbConnection::new().unwrap();
+
+    let result = cached_test_path(&mut conn, cache.path(), "substitute-eval", true);
+    let info_path = cache.path().join(info_location(&result));
+    let mut info: CacheInfo = serde_json::from_slice(&std::fs::read(&info_path).unwrap()).unwrap();
+    let eval = info.eval.take().unwrap();
+    info.eval = Some(eval.replace("url = \"\"", "url = \"https://attacker.example.com\""));
+    std::fs::write(&info_path, serde_json::to_vec(&info).unwrap()).unwrap();
+
+    let (substituted, mut conn) = test_substitute(&base, &result, conn);
+    let err = substituted.unwrap_err().to_string();
+    assert!(err.contains("Hash mismatch for the unit"), "{}", err);
+    assert!(!conn.is_db_path(result.store_path()).unwrap());
+    assert!(!result.eval_path().exists());
+}
+
+#[test]
+fn test_substitute_http() {
+    let _store = crate::layout::test_store();
+    let cache = tempfile::tempdir().unwrap();
+    let base = serve_dir(cache.path());
+    let mut conn = DbConnection::new().unwrap();
+
+    let result = cached_test_path(&mut conn, cache.path(), "substitute-http", true);
+    let (substituted, mut conn) = test_substitute(&base, &result, conn);
+    assert!(substituted.unwrap());
+    assert!(conn.is_db_path(result.store_path()).unwrap());
+
+    let missing = MiqResult::create("not-cached", &"not-cached");
+    let (substituted, _) = test_substitute(&base, &missing, conn);
+    assert!(!substituted.unwrap());
+}
@@ -0,0 +1,191 @@
+//! User configuration, read from a TOML file.
+//!
+//! The first file that exists is used:
+//! - `$MIQ_CONFIG`
+//! - `$XDG_CONFIG_HOME/miq/config.toml`, or `~/.config/miq/config.toml`
+//! - `/etc/miq/config.toml`
+
+use std::path::PathBuf;
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Deserializer};
+use tracing::debug;
+use url::Url;
+
+use crate::signing::PublicKey;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Hard-link identical files after every path is registered, like `miq store optimise`
+    pub auto_optimise: bool,
+
+    /// Binary caches to download outputs from before building them, tried in order
+    pub substituters: Vec<Url>,
+
+    /// Keys whose signatures are trusted, as `<name>:<base64>`
+    pub trusted_public_keys: Vec<PublicKey>,
+
+    /// Refuse to substitute or import paths that no trusted key has signed
+    pub require_sigs: bool,
+
+    /// Secret keys to sign paths with when exporting or copying them
+    pub secret_key_files: Vec<PathBuf>,
+
+    /// Root of the store, instead of /miq
+    pub store: Option<PathBuf>,
+
+    /// Keep the store in ~/.local/share/miq, or `store`, and mount it at /miq in a namespace
+    pub rootless: bool,
+
+    /// Free space to keep in the store and the temporary directory during builds, in bytes or
+    /// with a K, M, G or T suffix. See [crate::disk].
+    #[serde(deserialize_with = "deserialize_size")]
+    pub min_free: Option<u64>,
+
+    /// Free space to reach when collecting garbage because of `min-free`. Everything unrooted is
+    /// collected if it's not set.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub max_free: Option<u64>,
+
+    /// Group of the daemon socket, whose members can build and query through the daemon. Without
+    /// it, the socket has the primary group of the user that runs the daemon.
+    pub daemon_group: Option<String>,
+
+    /// Group whose members can also rebuild paths and collect garbage through the daemon, which
+    /// only the user that runs it can do otherwise
+    pub daemon_trusted_group: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            auto_optimise: false,
+            substituters: Vec::new(),
+            trusted_public_keys: Vec::new(),
+            require_sigs: true,
+            secret_key_files: Vec::new(),
+            store: None,
+            rootless: false,
+            min_free: None,
+            max_free: None,
+            daemon_group: None,
+            daemon_trusted_group: None,
+        }
+    }
+}
+
+/// A size as a number of bytes, or as a string like `10G`
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(text) => crate::gc::parse_size(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+fn candidates() -> Vec<PathBuf> {
+    if let Some(path) = std::env::var_os("MIQ_CONFIG") {
+        return vec![PathBuf::from(path)];
+    }
+
+    let mut result = Vec::new();
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        result.push(PathBuf::from(dir).join("miq/config.toml"));
+    } else if let Some(home) = std::env::var_os("HOME") {
+        result.push(PathBuf::from(home).join(".config/miq/config.toml"));
+    }
+    result.push(PathBuf::from("/etc/miq/config.toml"));
+    result
+}
+
+impl Config {
+    fn read() -> Result<Self> {
+        for path in candidates() {
+            let text = match std::fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err).wrap_err(format!("Reading {:?}", path)),
+            };
+            let config = toml::from_str(&text).wrap_err(format!("Parsing {:?}", path))?;
+            debug!(?path, ?config, "Loaded config");
+            return Ok(config);
+        }
+
+        Ok(Self::default())
+    }
+}
+
+/// Read the config file, so that errors are reported early
+pub fn load() -> Result<()> {
+    CONFIG.get_or_try_init(Config::read)?;
+    Ok(())
+}
+
+/// The loaded config, or the default one if [load] wasn't called
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(default_config)
+}
+
+#[cfg(not(test))]
+fn default_config() -> Config {
+    Config::default()
+}
+
+/// Tests trust the key they sign with, see [crate::signing::test_key]
+#[cfg(test)]
+fn default_config() -> Config {
+    Config {
+        trusted_public_keys: vec![crate::signing::test_key().public()],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_config_parse() {
+    let config: Config = toml::from_str("auto-optimise = true").unwrap();
+    assert!(config.auto_optimise);
+
+    let config: Config = toml::from_str("").unwrap();
+    assert!(!config.auto_optimise);
+    assert!(config.substituters.is_empty());
+
+    let config: Config =
+        toml::from_str(r#"substituters = ["file:///srv/cache", "https://cache.example.com"]"#)
+            .unwrap();
+    assert_eq!(config.substituters[0].scheme(), "file");
+
+    assert!(config.require_sigs);
+
+    let config: Config = toml::from_str(
+        r#"trusted-public-keys = ["test-1:6kpsY5ZB9Wy1wm1u3ZHz/0+SWJz4dwRcFFUcY9aC4sw="]"#,
+    )
+    .unwrap();
+    assert_eq!(config.trusted_public_keys[0].name, "test-1");
+    assert!(toml::from_str::<Config>(r#"trusted-public-keys = ["test-1:aGVsbG8="]"#).is_err());
+
+    let config: Config = toml::from_str(r#"store = "/tmp/miq""#).unwrap();
+    assert_eq!(config.store, Some(PathBuf::from("/tmp/miq")));
+
+    let config: Config = toml::from_str("min-free = \"10G\"\nmax-free = 1024").unwrap();
+    assert_eq!(config.min_free, Some(10 << 30));
+    assert_eq!(config.max_free, Some(1024));
+    assert!(toml::from_str::<Config>(r#"min-free = "lots""#).is_err());
+
+    assert!(toml::from_str::<Config>("auto_optimise = true").is_err());
+}
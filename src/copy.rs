@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Help, Result};
+use tracing::{debug, info};
+use url::Url;
+
+use crate::db::DbConnection;
+use crate::eval::{RefToUnit, UnitRef};
+
+#[derive(Debug, clap::Args)]
+/// Copy the closure of store paths to a binary cache
+pub struct Args {
+    /// Cache to copy to, like file:///srv/miq-cache
+    #[arg(long)]
+    to: Url,
+
+    /// Store paths or unitrefs to copy, which must be built already
+    #[arg(required = true)]
+    paths: Vec<String>,
+}
+
+/// Resolve a store path or a unitref to a store path
+fn resolve(target: &str) -> Result<PathBuf> {
+    let path = crate::db::normalize_store_path(target);
+    if path.starts_with(crate::hash::HASH_STORE_DIR) {
+        return Ok(path);
+    }
+
+    let unit = UnitRef::from_str(target)?
+        .ref_to_unit()
+        .wrap_err(format!("Evaluating {}", target))?;
+    Ok(unit.result().store_path().to_path_buf())
+}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        let dir = crate::cache::local_cache_dir(&self.to)?;
+        let _lock = crate::lock::StoreLock::shared()?;
+        let mut conn = DbConnection::new()?;
+
+        let mut roots = Vec::new();
+        for target in &self.paths {
+            let path = resolve(target)?;
+            if !conn.is_db_path(&path)? {
+                let err = eyre!("{:?} is not in the store", path);
+                if path.as_os_str() == target.as_str() {
+                    return Err(err);
+                }
+                return Err(err.suggestion(format!("Build it first with miq build {}", target)));
+            }
+            roots.push(path);
+        }
+        let _roots = crate::gc::TempRoots::new(&roots)?;
+
+        let (copied, total) = copy(&conn, &dir, &roots)?;
+        println!(
+            "{} paths copied, {} already present",
+            copied,
+            total - copied
+        );
+
+        Ok(())
+    }
+}
+
+/// Write the closure of `roots` to the cache at `dir` and update its index, returning how many
+/// paths were copied and how many are in the closure
+pub fn copy<P: AsRef<Path>>(
+    conn: &DbConnection,
+    dir: &Path,
+    roots: &[P],
+) -> Result<(usize, usize)> {
+    let closure = conn.closure(roots)?;
+    // Referrers come first, so reverse it to copy references first
+    let mut order = conn.deletion_order(closure)?;
+    order.reverse();
+
+    let mut copied = 0;
+    for path in &order {
+        if crate::cache::write_path(dir, conn, path)? {
+            info!(?path, "Copied");
+            copied += 1;
+        }
+    }
+
+    let index = crate::cache::write_index(dir)?;
+    debug!(paths = index.paths.len(), "Wrote index");
+
+    Ok((copied, order.len()))
+}
+
+#[test]
+fn test_copy() {
+    use crate::eval::MiqResult;
+
+    let _store = crate::layout::test_store();
+    let cache = tempfile::tempdir().unwrap();
+    let mut conn = DbConnection::new().unwrap();
+    let first = crate::db::add_test_path(&mut conn, &MiqResult::create("copy-1", &1), "1");
+    let second = crate::db::add_test_path(&mut conn, &MiqResult::create("copy-2", &2), "2");
+
+    assert_eq!(copy(&conn, cache.path(), &[&first]).unwrap(), (1, 1));
+    let index: crate::cache::CacheIndex =
+        serde_json::from_slice(&std::fs::read(cache.path().join("index.json")).unwrap()).unwrap();
+    assert_eq!(index.paths.keys().collect::<Vec<_>>(), [&first]);
+
+    // The path that is already in the cache is not written again
+    let archive = cache
+        .path()
+        .join(crate::cache::archive_location(&MiqResult::create(
+            "copy-1", &1,
+        )));
+    std::fs::write(&archive, "untouched").unwrap();
+
+    assert_eq!(
+        copy(&conn, cache.path(), &[&first, &second]).unwrap(),
+        (1, 2)
+    );
+    assert_eq!(std::fs::read_to_string(&archive).unwrap(), "untouched");
+    let index: crate::cache::CacheIndex =
+        serde_json::from_slice(&std::fs::read(cache.path().join("index.json")).unwrap()).unwrap();
+    assert_eq!(index.paths.keys().collect::<Vec<_>>(), [&first, &second]);
+}
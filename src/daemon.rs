@@ -0,0 +1,397 @@
+//! A daemon that owns the store, so that users that can't write to `/miq` can still build.
+//!
+//! Clients connect to `daemon.sock` in the root of the store, or `$MIQ_DAEMON_SOCKET`, and speak
+//! newline-delimited JSON:
+//!
+//! ```text
+//! -> {"version": 1, "request": {"op": "build", ...}}
+//! <- {"type": "output", "line": "..."}
+//! <- {"type": "done", "path": "/miq/store/..."}
+//! ```
+//!
+//! Any number of output lines is followed by a `done` or an `error`, and then the connection is
+//! closed. Requests with a different [PROTOCOL_VERSION] are refused.
+//!
+//! The socket can only be used by the members of `daemon-group` from the config. Their Lua files
+//! are evaluated in a sandbox, and only read if they could read them themselves, see [open_as].
+//! Only the user that runs the daemon, root and the members of `daemon-trusted-group` can make
+//! requests that remove paths, see [authorize].
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{bail, ensure, eyre, Context};
+use color_eyre::{Help, Result};
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use nix::fcntl::{openat, OFlag};
+use nix::sys::socket::{getsockopt, sockopt};
+use nix::sys::stat::Mode;
+use nix::unistd::{Gid, Group, Uid, User};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::db::DbConnection;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, clap::Args)]
+/// Serve build, query and gc requests from other users over a Unix socket
+pub struct Args {
+    /// Socket to listen on, instead of daemon.sock in the root of the store
+    #[arg(long, env = "MIQ_DAEMON_SOCKET")]
+    socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Request {
+    Build(crate::build::Args),
+    Query(crate::db::QueryArgs),
+    Gc(crate::gc::Args),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope<T> {
+    version: u32,
+    request: T,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Response {
+    Output { line: String },
+    Done { path: Option<String> },
+    Error { message: String },
+}
+
+/// Where commands send the lines meant for the user, which the daemon forwards to the client
+pub type Output<'a> = &'a mut dyn FnMut(String) -> Result<()>;
+
+/// An [Output] for commands that run in the CLI
+pub fn print_line(line: String) -> Result<()> {
+    println!("{}", line);
+    Ok(())
+}
+
+fn socket_path() -> PathBuf {
+    std::env::var_os("MIQ_DAEMON_SOCKET")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| crate::layout::get().daemon_socket())
+}
+
+pub struct Client {
+    stream: UnixStream,
+}
+
+impl Client {
+    /// Connect to the daemon, if one is running
+    pub fn connect() -> Result<Option<Self>> {
+        let socket = socket_path();
+        match UnixStream::connect(&socket) {
+            Ok(stream) => {
+                debug!(?socket, "Using the daemon");
+                Ok(Some(Self { stream }))
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err).wrap_err(format!("Connecting to the daemon at {:?}", socket)),
+        }
+    }
+
+    /// Send a request, printing its output, and return the path it produced if any
+    pub fn request(self, request: Request) -> Result<Option<String>> {
+        let envelope = Envelope {
+            version: PROTOCOL_VERSION,
+            request,
+        };
+        let mut line = serde_json::to_string(&envelope)?;
+        line.push('\n');
+        (&self.stream).write_all(line.as_bytes())?;
+
+        for line in BufReader::new(&self.stream).lines() {
+            match serde_json::from_str(&line?)? {
+                Response::Output { line } => println!("{}", line),
+                Response::Done { path } => return Ok(path),
+                Response::Error { message } => bail!("The daemon failed: {}", message),
+            }
+        }
+
+        bail!("The daemon closed the connection")
+    }
+}
+
+fn parse_request(line: &str) -> Result<Request> {
+    let envelope: Envelope<serde_json::Value> = serde_json::from_str(line)?;
+    if envelope.version != PROTOCOL_VERSION {
+        bail!(
+            "Unsupported protocol version {}, the daemon speaks version {}",
+            envelope.version,
+            PROTOCOL_VERSION
+        );
+    }
+    Ok(serde_json::from_value(envelope.request)?)
+}
+
+fn send(stream: &mut UnixStream, response: &Response) -> Result<()> {
+    let mut line = serde_json::to_string(response)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+fn group_gid(name: &str) -> Result<Gid> {
+    match Group::from_name(name)? {
+        Some(group) => Ok(group.gid),
+        None => bail!("The group {:?} from the config doesn't exist", name),
+    }
+}
+
+/// The groups of the user, including its primary group
+fn groups(uid: Uid) -> Result<Vec<Gid>> {
+    let Some(user) = User::from_uid(uid)? else {
+        return Ok(Vec::new());
+    };
+    let name = CString::new(user.name)?;
+    Ok(nix::unistd::getgrouplist(&name, user.gid)?)
+}
+
+/// Whether the user is a member of the group, as its primary group or not
+fn in_group(uid: Uid, group: &str) -> Result<bool> {
+    Ok(groups(uid)?.contains(&group_gid(group)?))
+}
+
+/// Open a file that the daemon reads for the user that sent a request, only if the permissions of
+/// the file and of the directories above it let them read it. The path is walked without following
+/// symlinks, so that it can't be swapped for another one once checked.
+pub fn open_as(path: &Path, uid: Uid) -> Result<File> {
+    let path = path
+        .canonicalize()
+        .wrap_err(format!("Reading {:?}", path))?;
+    let groups = groups(uid)?;
+    let allows = |file: &File, bits: u32| -> Result<bool> {
+        let meta = file.metadata()?;
+        let shift = if meta.uid() == uid.as_raw() {
+            6
+        } else if groups.contains(&Gid::from_raw(meta.gid())) {
+            3
+        } else {
+            0
+        };
+        Ok(uid.is_root() || (meta.mode() >> shift) & bits == bits)
+    };
+    let denied = || eyre!("{:?} can't be read by the user that sent the request", path);
+
+    let mut file = File::open("/")?;
+    for component in path.components().skip(1) {
+        ensure!(allows(&file, 0o1)?, denied());
+        // Not blocking on a FIFO until someone writes to it
+        let flags = OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC;
+        let fd = openat(
+            file.as_raw_fd(),
+            component.as_os_str(),
+            flags,
+            Mode::empty(),
+        )
+        .wrap_err(format!("Reading {:?}", path))?;
+        file = unsafe { File::from_raw_fd(fd) };
+    }
+    ensure!(file.metadata()?.is_file(), "{:?} is not a file", path);
+    ensure!(allows(&file, 0o4)?, denied());
+
+    Ok(file)
+}
+
+impl Request {
+    /// Whether the request can remove paths, which other users might need
+    fn removes_paths(&self) -> bool {
+        match self {
+            Request::Build(args) => args.removes_paths(),
+            Request::Gc(_) => true,
+            Request::Query(_) => false,
+        }
+    }
+}
+
+/// Refuse requests that remove paths from users other than the one running the daemon, root and
+/// the members of `daemon-trusted-group`
+fn authorize(request: &Request, uid: Uid) -> Result<()> {
+    if !request.removes_paths() || uid.is_root() || uid == nix::unistd::geteuid() {
+        return Ok(());
+    }
+
+    let trusted = match &crate::config::get().daemon_trusted_group {
+        Some(group) => in_group(uid, group)?,
+        None => false,
+    };
+    if trusted {
+        return Ok(());
+    }
+
+    Err(
+        eyre!("Only trusted users can rebuild paths or collect garbage through the daemon")
+            .suggestion("Add the user to daemon-trusted-group in the config of the daemon"),
+    )
+}
+
+fn serve(request: Request, uid: Uid, output: Output) -> Result<Option<String>> {
+    authorize(&request, uid)?;
+
+    match request {
+        Request::Build(args) => {
+            let bars = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+            let unit = args.unit_sandboxed(uid)?;
+            let unit = tokio::runtime::Runtime::new()?.block_on(args.run(
+                unit,
+                &bars,
+                output,
+                Some(uid),
+            ))?;
+            let path = unit.result().store_path().to_string_lossy().into_owned();
+            Ok(Some(path))
+        }
+        Request::Query(args) => {
+            args.run(&DbConnection::new()?, output)?;
+            Ok(None)
+        }
+        Request::Gc(args) => {
+            args.run(output)?;
+            Ok(None)
+        }
+    }
+}
+
+fn handle(mut stream: UnixStream) -> Result<()> {
+    let peer = getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials)?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    let result = parse_request(&line).and_then(|request| {
+        info!(uid = peer.uid(), ?request, "Serving");
+        let mut writer = stream.try_clone()?;
+        serve(request, Uid::from_raw(peer.uid()), &mut |line| {
+            send(&mut writer, &Response::Output { line })
+        })
+    });
+
+    let response = match result {
+        Ok(path) => Response::Done { path },
+        Err(err) => {
+            warn!(uid = peer.uid(), "Request failed: {:#}", err);
+            Response::Error {
+                message: format!("{:#}", err),
+            }
+        }
+    };
+    send(&mut stream, &response)
+}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        let socket = self
+            .socket
+            .clone()
+            .unwrap_or_else(|| crate::layout::get().daemon_socket());
+
+        if UnixStream::connect(&socket).is_ok() {
+            return Err(eyre!("A daemon is already listening on {:?}", socket));
+        }
+        match std::fs::remove_file(&socket) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).wrap_err(format!("Removing the stale socket {:?}", socket))
+            }
+            _ => {}
+        }
+
+        let listener =
+            UnixListener::bind(&socket).wrap_err(format!("Listening on {:?}", socket))?;
+        if let Some(group) = &crate::config::get().daemon_group {
+            nix::unistd::chown(&socket, None, Some(group_gid(group)?))
+                .wrap_err(format!("Giving {:?} to the group {:?}", socket, group))?;
+        }
+        std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o660))?;
+        println!("Listening on {:?}", socket);
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            std::thread::spawn(move || {
+                if let Err(err) = handle(stream) {
+                    warn!("Connection failed: {:#}", err);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_request() {
+    let line = r#"{"version": 1, "request": {"op": "query", "path": "/miq/store/foo",
+        "references": true, "referrers": false, "closure": false, "hash": false, "size": false}}"#;
+    assert!(matches!(parse_request(line).unwrap(), Request::Query(_)));
+
+    let line = r#"{"version": 2, "request": {"op": "gc", "dry_run": true}}"#;
+    assert!(parse_request(line).is_err());
+
+    let line = r#"{"version": 1, "request": {"op": "gc", "dry_run": true, "max_freed": null}}"#;
+    assert!(matches!(parse_request(line).unwrap(), Request::Gc(_)));
+
+    let response = serde_json::to_string(&Response::Done { path: None }).unwrap();
+    assert_eq!(response, r#"{"type":"done","path":null}"#);
+}
+
+#[test]
+fn test_authorize() {
+    let gc = r#"{"version": 1, "request": {"op": "gc", "dry_run": false, "max_freed": null}}"#;
+    let gc = parse_request(gc).unwrap();
+    let query = r#"{"version": 1, "request": {"op": "query", "path": "/miq/store/foo",
+        "references": true, "referrers": false, "closure": false, "hash": false, "size": false}}"#;
+    let query = parse_request(query).unwrap();
+
+    let owner = nix::unistd::geteuid();
+    // Not a user of this machine, and the config of the tests has no trusted group
+    let other = Uid::from_raw(u32::MAX - 1);
+
+    assert!(authorize(&gc, owner).is_ok());
+    assert!(authorize(&gc, Uid::from_raw(0)).is_ok());
+    assert!(authorize(&query, other).is_ok());
+    let err = authorize(&gc, other).unwrap_err().to_string();
+    assert!(err.contains("Only trusted users"), "{}", err);
+}
+
+#[test]
+fn test_open_as() {
+    use std::fs::Permissions;
+
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("pkgs.lua");
+    std::fs::write(&file, "").unwrap();
+    std::fs::set_permissions(&file, Permissions::from_mode(0o600)).unwrap();
+    let owner = nix::unistd::geteuid();
+    let other = Uid::from_raw(u32::MAX - 1);
+
+    assert!(open_as(&file, owner).is_ok());
+    std::fs::set_permissions(&file, Permissions::from_mode(0o644)).unwrap();
+    std::fs::set_permissions(dir.path(), Permissions::from_mode(0o700)).unwrap();
+    let err = open_as(&file, other).unwrap_err().to_string();
+    assert!(err.contains("can't be read"), "{}", err);
+
+    std::fs::set_permissions(dir.path(), Permissions::from_mode(0o711)).unwrap();
+    assert!(open_as(&file, other).is_ok());
+    std::fs::set_permissions(&file, Permissions::from_mode(0o640)).unwrap();
+    assert!(open_as(&file, other).is_err());
+
+    assert!(open_as(dir.path(), owner).is_err());
+}
@@ -1,17 +1,24 @@
 use std::cell::RefCell;
+use std::collections::BTreeSet;
 use std::ops::DerefMut;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use color_eyre::eyre::{bail, eyre};
-use color_eyre::Result;
+use color_eyre::eyre::{bail, eyre, Context};
+use color_eyre::{Help, Result};
 use diesel::migration::MigrationVersion;
 use diesel::prelude::*;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use serde::{Deserialize, Serialize};
 use tracing::{debug, info, trace, warn};
 
 use crate::build;
-use crate::schema_db::store;
+use crate::eval::{MiqResult, RefToUnit, UnitRef};
+use crate::export::PathMetadata;
+use crate::lock::StoreLock;
 use crate::schema_db::store::dsl::*;
+use crate::schema_db::{refs, signatures, store};
+use crate::schema_eval::Unit;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
@@ -34,6 +41,26 @@ enum CliSubcommand {
     /// Manually remove a path
     #[command(visible_alias("rm"))]
     Remove(RemoveArgs),
+    /// Query the references between paths
+    #[command(visible_alias("q"))]
+    Query(QueryArgs),
+    /// Write the canonical archive of a path to stdout
+    Dump(DumpArgs),
+    /// Recreate a path from an archive read from stdin
+    Restore(RestoreArgs),
+    /// Check paths against their recorded hash
+    Verify(crate::verify::Args),
+    /// Replace identical files with hard links
+    #[command(visible_alias("optimize"))]
+    Optimise(crate::optimise::Args),
+    /// Write the closure of some paths to stdout, to import them on another machine
+    Export(crate::export::ExportArgs),
+    /// Register the paths of an export read from stdin
+    Import(crate::export::ImportArgs),
+    /// Show what changed between the closures of two paths
+    DiffClosures(crate::diff_closures::Args),
+    /// Show the size of paths and of their closures
+    Du(crate::du::Args),
 }
 
 #[derive(Debug, clap::Args)]
@@ -45,17 +72,317 @@ struct AddArgs {
 
 #[derive(Debug, clap::Args)]
 struct RemoveArgs {
-    #[arg(value_hint = clap::ValueHint::DirPath)]
+    #[arg(
+        value_hint = clap::ValueHint::DirPath,
+        conflicts_with_all = ["all", "all_packages", "unit_type", "name", "older_than", "orphaned"],
+    )]
     /// Path to remove from the store
     path: Option<PathBuf>,
 
     /// Remove all known paths (wipe store)
-    #[arg(long)]
+    #[arg(
+        long,
+        conflicts_with_all = ["all_packages", "unit_type", "name", "older_than", "orphaned"],
+    )]
     all: bool,
 
-    /// Remove all packages
-    #[arg(long)]
+    /// Remove all packages, same as --type=package
+    #[arg(long, conflicts_with = "unit_type")]
     all_packages: bool,
+
+    /// Only remove paths built from this type of unit
+    #[arg(long = "type", value_enum)]
+    unit_type: Option<UnitType>,
+
+    /// Only remove paths whose name matches this glob, like 'gcc-*'
+    #[arg(long)]
+    name: Option<glob::Pattern>,
+
+    /// Only remove paths registered longer ago than this, like '30days' or '12h'
+    #[arg(long, value_parser = humantime::parse_duration)]
+    older_than: Option<Duration>,
+
+    /// Only remove paths that were not built from a current unit. The current units are the ones
+    /// of the paths a GC root keeps, of the units given with --keep, and their dependencies.
+    #[arg(long)]
+    orphaned: bool,
+
+    /// Unitrefs whose units, and their dependencies, are current for --orphaned
+    #[arg(long, value_name = "UNITREF", requires = "orphaned")]
+    keep: Vec<UnitRef>,
+
+    /// Remove the path even if other paths or GC roots still reference it
+    #[arg(long, short)]
+    force: bool,
+
+    /// Also remove every path that references it, directly or not
+    #[arg(long, short, conflicts_with = "force")]
+    recursive: bool,
+
+    /// Only print the paths that would be removed
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum UnitType {
+    Package,
+    Fetch,
+    Env,
+}
+
+impl RemoveArgs {
+    fn has_selectors(&self) -> bool {
+        self.all_packages
+            || self.unit_type.is_some()
+            || self.name.is_some()
+            || self.older_than.is_some()
+            || self.orphaned
+    }
+
+    /// Results of the units that are current for --orphaned
+    fn current_units(&self, conn: &DbConnection) -> Result<BTreeSet<MiqResult>> {
+        let mut roots = BTreeSet::new();
+        for path in conn.closure(crate::gc::find_roots(true)?)? {
+            match MiqResult::from_store_path(&path) {
+                // Like profile generations, which are not built from a unit
+                Some(result) if !result.eval_path().try_exists()? => {}
+                Some(result) => {
+                    roots.insert(result);
+                }
+                None => {}
+            }
+        }
+        for unit_ref in &self.keep {
+            let unit = unit_ref
+                .ref_to_unit()
+                .wrap_err(format!("Evaluating {}", unit_ref))?;
+            roots.insert(unit.result().clone());
+        }
+
+        crate::eval::deps_closure(&roots)
+    }
+
+    /// Whether `path` matches every selector that was given. `current` are the results of the
+    /// current units, see [RemoveArgs::current_units].
+    fn matches(&self, path: &StorePath, now: i64, current: &BTreeSet<MiqResult>) -> Result<bool> {
+        let Some(result) = MiqResult::from_store_path(&path.store_path) else {
+            warn!(?path, "Not a store path, skipping");
+            return Ok(false);
+        };
+
+        if let Some(pattern) = &self.name {
+            if !pattern.matches(result.name()) {
+                return Ok(false);
+            }
+        }
+
+        if let Some(older_than) = self.older_than {
+            let age = now - path.registered_at;
+            if age < older_than.as_secs() as i64 {
+                return Ok(false);
+            }
+        }
+
+        if self.orphaned && current.contains(&result) {
+            return Ok(false);
+        }
+
+        let wanted_type = match self.unit_type {
+            _ if self.all_packages => Some(UnitType::Package),
+            t => t,
+        };
+
+        if let Some(wanted_type) = wanted_type {
+            if !result.eval_path().try_exists()? {
+                return Ok(false);
+            }
+            let actual_type = match Unit::from_result(&result)? {
+                Unit::PackageUnit(_) => UnitType::Package,
+                Unit::FetchUnit(_) => UnitType::Fetch,
+                Unit::EnvUnit(_) => UnitType::Env,
+            };
+            if actual_type != wanted_type {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn run(&self, conn: &mut DbConnection) -> Result<()> {
+        let selected: BTreeSet<String> = match self {
+            RemoveArgs {
+                path: Some(path), ..
+            } => {
+                let path_normalized = normalize_store_path(path);
+                BTreeSet::from([path_normalized.to_str().unwrap().to_owned()])
+            }
+            RemoveArgs { all: true, .. } => {
+                conn.list()?.into_iter().map(|p| p.store_path).collect()
+            }
+            _ if self.has_selectors() => {
+                let now = unix_now();
+                let current = match self.orphaned {
+                    true => self.current_units(conn)?,
+                    false => BTreeSet::new(),
+                };
+                let mut selected = BTreeSet::new();
+                for path in conn.list()? {
+                    if self.matches(&path, now, &current)? {
+                        selected.insert(path.store_path);
+                    }
+                }
+                selected
+            }
+            _ => {
+                let err = eyre!(clap::error::ErrorKind::TooFewValues)
+                    .wrap_err("Read the --help section for usage");
+                return Err(err);
+            }
+        };
+        debug!(?selected);
+
+        let selected = if self.recursive {
+            conn.referrers_closure(&selected)?
+        } else {
+            selected
+        };
+
+        // Profile generations have no unit, so they are orphaned too
+        let live = conn.closure(crate::gc::find_roots(true)?)?;
+
+        for elem in conn.deletion_order(selected.clone())? {
+            if !self.force && live.contains(&elem) {
+                if self.path.is_some() {
+                    return Err(eyre!(
+                        "Refusing to remove {:?}, it is reachable from a GC root",
+                        elem
+                    )
+                    .suggestion("Remove the root, or the profile generation that installs it")
+                    .suggestion("Use --force to remove it anyway, breaking the root"));
+                }
+                warn!(?elem, "Reachable from a GC root, skipping");
+                continue;
+            }
+
+            if !self.force {
+                // Referrers that are also selected are removed before this path
+                let kept_referrers: Vec<String> = conn
+                    .referrers(&elem)?
+                    .into_iter()
+                    .filter(|r| !selected.contains(r))
+                    .collect();
+                if !kept_referrers.is_empty() {
+                    if self.path.is_some() {
+                        return Err(eyre!(
+                            "Refusing to remove {:?}, it is still referenced by:\n{}",
+                            elem,
+                            kept_referrers.join("\n")
+                        )
+                        .suggestion("Use --recursive to remove the referrers too")
+                        .suggestion("Use --force to remove it anyway, breaking the referrers"));
+                    }
+                    warn!(?elem, ?kept_referrers, "Still referenced, skipping");
+                    continue;
+                }
+            }
+
+            if self.dry_run {
+                println!("{}", elem);
+                continue;
+            }
+
+            info!(?elem, "Removing");
+            if self.force {
+                conn.remove_unchecked(&elem)?;
+            } else {
+                conn.remove(&elem)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, clap::Args, Serialize, Deserialize)]
+#[command(group(clap::ArgGroup::new("query").required(true)))]
+pub struct QueryArgs {
+    #[arg(value_hint = clap::ValueHint::DirPath)]
+    /// Store path to query
+    path: PathBuf,
+
+    /// Paths that this path references at runtime
+    #[arg(long, group = "query")]
+    references: bool,
+
+    /// Paths that reference this path at runtime
+    #[arg(long, group = "query")]
+    referrers: bool,
+
+    /// All the paths that this path needs at runtime, including itself
+    #[arg(long, group = "query")]
+    closure: bool,
+
+    /// Hash of the archive of this path
+    #[arg(long, group = "query")]
+    hash: bool,
+
+    /// Size of the archive of this path, in bytes
+    #[arg(long, group = "query")]
+    size: bool,
+}
+
+impl QueryArgs {
+    pub fn run(&self, conn: &DbConnection, output: crate::daemon::Output) -> Result<()> {
+        let path_normalized = normalize_store_path(&self.path);
+        let Some(info) = conn.path_info(&path_normalized)? else {
+            bail!("{:?} is not a registered path", path_normalized);
+        };
+
+        if self.hash || self.size {
+            let value = if self.hash {
+                info.archive_hash
+            } else {
+                info.archive_size.map(|s| s.to_string())
+            };
+            let value = value.ok_or_else(|| {
+                eyre!(
+                    "{:?} was registered without an archive hash",
+                    path_normalized
+                )
+            })?;
+            return output(value);
+        }
+
+        let result = if self.references {
+            conn.references(&path_normalized)?
+        } else if self.referrers {
+            conn.referrers(&path_normalized)?
+        } else {
+            conn.closure([&path_normalized])?.into_iter().collect()
+        };
+
+        for path in result {
+            output(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct DumpArgs {
+    #[arg(value_hint = clap::ValueHint::AnyPath)]
+    /// Path to serialize, it doesn't need to be in the store
+    path: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+struct RestoreArgs {
+    #[arg(value_hint = clap::ValueHint::AnyPath)]
+    /// Where to unpack the archive, must not exist
+    path: PathBuf,
 }
 
 #[derive(Debug, clap::Args)]
@@ -69,6 +396,11 @@ impl crate::Main for Args {
     fn main(&self) -> Result<()> {
         let conn = &mut DbConnection::new()?;
 
+        let _lock = match &self.action {
+            CliSubcommand::Add(_) | CliSubcommand::Remove(_) => Some(StoreLock::shared()?),
+            _ => None,
+        };
+
         match &self.action {
             CliSubcommand::List => {
                 let all = conn.list()?;
@@ -77,36 +409,35 @@ impl crate::Main for Args {
                 }
             }
             CliSubcommand::Add(args) => {
-                let path_normalized = fix_dir_trailing_slash(&args.path);
+                let path_normalized = normalize_store_path(&args.path);
                 conn.add(path_normalized)?;
             }
             CliSubcommand::IsPath(args) => {
-                let path_normalized = fix_dir_trailing_slash(&args.path);
+                let path_normalized = normalize_store_path(&args.path);
                 let result = conn.is_db_path(path_normalized)?;
                 info!("{:?}", result);
             }
-            CliSubcommand::Remove(args) => {
-                match args {
-                    RemoveArgs {
-                        path: Some(path), ..
-                    } => {
-                        let path_normalized = fix_dir_trailing_slash(&path);
-                        conn.remove(path_normalized)?;
-                    }
-                    RemoveArgs { all: true, .. } => {
-                        let all = conn.list()?;
-                        debug!(?all);
-                        for elem in all {
-                            info!(?elem, "Removing");
-                            conn.remove(&elem.store_path)?;
-                        }
-                    }
-                    _ => {
-                        let err = eyre!(clap::error::ErrorKind::TooFewValues)
-                            .wrap_err("Read the --help section for usage");
-                        return Err(err);
-                    }
-                };
+            CliSubcommand::Remove(args) => args.run(conn)?,
+            CliSubcommand::Query(args) => match crate::daemon::Client::connect()? {
+                Some(client) => {
+                    client.request(crate::daemon::Request::Query(args.clone()))?;
+                }
+                None => args.run(conn, &mut crate::daemon::print_line)?,
+            },
+            CliSubcommand::Dump(args) => {
+                let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+                crate::archive::dump(&args.path, &mut stdout)?;
+                std::io::Write::flush(&mut stdout)?;
+            }
+            CliSubcommand::Verify(args) => crate::Main::main(args)?,
+            CliSubcommand::Optimise(args) => crate::Main::main(args)?,
+            CliSubcommand::Export(args) => crate::Main::main(args)?,
+            CliSubcommand::Import(args) => crate::Main::main(args)?,
+            CliSubcommand::DiffClosures(args) => crate::Main::main(args)?,
+            CliSubcommand::Du(args) => crate::Main::main(args)?,
+            CliSubcommand::Restore(args) => {
+                let stdin = std::io::BufReader::new(std::io::stdin().lock());
+                crate::archive::restore(stdin, &args.path)?;
             }
         }
 
@@ -118,12 +449,56 @@ impl crate::Main for Args {
 #[diesel(table_name = store)]
 pub struct StorePath {
     pub store_path: String,
+    /// Seconds since the epoch
+    pub registered_at: i64,
+    /// SRI hash of the archive of the path, see [crate::archive]
+    pub archive_hash: Option<String>,
+    pub archive_size: Option<i64>,
+}
+
+impl StorePath {
+    /// Size of the path, as recorded when it was registered. Paths registered before sizes were
+    /// recorded are measured on disk instead.
+    pub fn size(&self) -> Result<u64> {
+        match self.archive_size {
+            Some(size) => Ok(size as u64),
+            None => crate::gc::path_size(crate::layout::get().physical(&self.store_path)),
+        }
+    }
 }
 
 #[derive(Insertable)]
 #[diesel(table_name = store)]
 pub struct NewPath {
     pub store_path: String,
+    pub registered_at: i64,
+    pub archive_hash: Option<String>,
+    pub archive_size: Option<i64>,
+}
+
+impl NewPath {
+    pub fn new(path: String, integrity: &crate::hash::Integrity, size: u64) -> Self {
+        Self {
+            store_path: path,
+            registered_at: unix_now(),
+            archive_hash: Some(integrity.to_string()),
+            archive_size: Some(size as i64),
+        }
+    }
+}
+
+#[derive(Debug, Queryable, Selectable, Insertable)]
+#[diesel(table_name = refs)]
+pub struct Ref {
+    pub referrer: String,
+    pub reference: String,
+}
+
+#[derive(Debug, Queryable, Selectable, Insertable)]
+#[diesel(table_name = signatures)]
+pub struct PathSignature {
+    pub store_path: String,
+    pub signature: String,
 }
 
 pub struct DbConnection {
@@ -132,10 +507,16 @@ pub struct DbConnection {
 
 impl DbConnection {
     pub fn new() -> Result<Self> {
-        let database_url =
-            std::env::var("MIQ_DATABASE_URL").unwrap_or_else(|_| String::from("/miq/db.sqlite"));
+        let database_url = std::env::var("MIQ_DATABASE_URL").unwrap_or_else(|_| {
+            let path = crate::layout::get().db_path();
+            path.to_str().unwrap().to_owned()
+        });
         trace!("DATABASE_URL: {:?}", database_url);
         let mut conn = diesel::SqliteConnection::establish(&database_url)?;
+        diesel::sql_query("PRAGMA foreign_keys = ON").execute(&mut conn)?;
+        // Other miq processes can write at the same time, wait for them instead of failing
+        diesel::sql_query("PRAGMA busy_timeout = 60000").execute(&mut conn)?;
+        diesel::sql_query("PRAGMA journal_mode = WAL").execute(&mut conn)?;
 
         match conn.run_pending_migrations(MIGRATIONS) {
             Ok::<Vec<MigrationVersion>, _>(migrations) => {
@@ -165,26 +546,119 @@ impl DbConnection {
 
         debug!("Adding {:?}", &path_str);
 
-        let input = NewPath {
-            store_path: path_str,
-        };
-
         if self.is_db_path(&path)? {
             warn!("Path is already on the store");
         } else {
+            let (integrity, size) =
+                crate::archive::hash_path(crate::layout::get().physical(&path))?;
+            let input = NewPath::new(path_str, &integrity, size);
+
             let db_response = diesel::insert_into(store::table)
                 .values(&input)
                 .execute(self.inner.borrow_mut().deref_mut())?;
 
             trace!(?db_response);
+            auto_optimise(&path);
         };
 
         Ok(())
     }
 
+    /// Register `path` while moving it into place from `temp`, where it was produced. The rename
+    /// happens inside of the transaction, so a path is never registered without being on disk.
+    pub fn add_from(&mut self, temp: &Path, path: &Path, references: Vec<String>) -> Result<()> {
+        let (integrity, size) = crate::archive::hash_path(temp)?;
+        let metadata = PathMetadata {
+            store_path: path.to_str().unwrap().to_owned(),
+            archive_hash: integrity,
+            archive_size: size,
+            references,
+            signatures: Vec::new(),
+            eval_hash: None,
+        };
+
+        self.add_many(&[&metadata], || {
+            let dest = crate::layout::get().physical(path);
+            std::fs::rename(temp, &dest).wrap_err(format!("Moving {:?} into {:?}", temp, dest))?;
+            Ok(())
+        })
+    }
+
+    /// Register paths whose hash is already known, along with their references and signatures.
+    /// Everything is done in one transaction, and `before_commit` runs last inside of it, so that
+    /// its failure leaves none of the paths registered.
+    pub fn add_many<F>(&mut self, paths: &[&PathMetadata], before_commit: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let conn = &mut *self.inner.borrow_mut();
+        conn.transaction::<_, color_eyre::Report, _>(|conn| {
+            for metadata in paths {
+                debug!(path = ?metadata.store_path, "Adding");
+                let path = NewPath::new(
+                    metadata.store_path.clone(),
+                    &metadata.archive_hash,
+                    metadata.archive_size,
+                );
+                diesel::insert_into(store::table)
+                    .values(&path)
+                    .execute(conn)?;
+
+                let new_refs: Vec<Ref> = metadata
+                    .references
+                    .iter()
+                    .map(|reference| Ref {
+                        referrer: path.store_path.clone(),
+                        reference: reference.clone(),
+                    })
+                    .collect();
+                diesel::insert_into(refs::table)
+                    .values(&new_refs)
+                    .execute(conn)?;
+
+                let new_signatures: Vec<PathSignature> = metadata
+                    .signatures
+                    .iter()
+                    .map(|signature| PathSignature {
+                        store_path: path.store_path.clone(),
+                        signature: signature.clone(),
+                    })
+                    .collect();
+                diesel::insert_or_ignore_into(signatures::table)
+                    .values(&new_signatures)
+                    .execute(conn)?;
+            }
+
+            before_commit()
+        })?;
+
+        for metadata in paths {
+            auto_optimise(&metadata.store_path);
+        }
+
+        Ok(())
+    }
+
+    /// Remove a path from disk and from the database, unless other paths reference it
     pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         let path = path.as_ref();
-        build::clean_path(&path)?;
+
+        let referrers = self.referrers(path)?;
+        if !referrers.is_empty() {
+            bail!(
+                "Refusing to remove {:?}, it is still referenced by:\n{}",
+                path,
+                referrers.join("\n")
+            );
+        }
+
+        self.remove_unchecked(path)
+    }
+
+    /// Remove a path without checking if other paths reference it
+    pub fn remove_unchecked<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        build::clean_path(crate::layout::get().physical(path))?;
 
         let path_str = path.to_str().unwrap();
 
@@ -207,10 +681,381 @@ impl DbConnection {
 
         Ok(!elements.is_empty())
     }
+
+    pub fn path_info<P: AsRef<Path>>(&self, path: P) -> Result<Option<StorePath>> {
+        let path_str = path.as_ref().to_str().unwrap();
+
+        let info = store
+            .filter(store_path.is(path_str))
+            .first::<StorePath>(self.inner.borrow_mut().deref_mut())
+            .optional()?;
+
+        Ok(info)
+    }
+
+    /// Paths that `path` references
+    pub fn references<P: AsRef<Path>>(&self, path: P) -> Result<Vec<String>> {
+        let path_str = path.as_ref().to_str().unwrap();
+
+        let result = refs::table
+            .filter(refs::referrer.is(path_str))
+            .select(refs::reference)
+            .order(refs::reference)
+            .load(self.inner.borrow_mut().deref_mut())?;
+
+        Ok(result)
+    }
+
+    /// Every reference between registered paths
+    pub fn all_refs(&self) -> Result<Vec<Ref>> {
+        let result = refs::table.load::<Ref>(self.inner.borrow_mut().deref_mut())?;
+        Ok(result)
+    }
+
+    /// Signatures of `path`, as `<key name>:<base64>`
+    pub fn signatures<P: AsRef<Path>>(&self, path: P) -> Result<Vec<String>> {
+        let path_str = path.as_ref().to_str().unwrap();
+
+        let result = signatures::table
+            .filter(signatures::store_path.is(path_str))
+            .select(signatures::signature)
+            .order(signatures::signature)
+            .load(self.inner.borrow_mut().deref_mut())?;
+
+        Ok(result)
+    }
+
+    /// Record signatures of `path`, ignoring the ones already known
+    pub fn add_signatures<P: AsRef<Path>>(&self, path: P, new: &[String]) -> Result<()> {
+        let path_str = path.as_ref().to_str().unwrap();
+
+        let new_signatures: Vec<PathSignature> = new
+            .iter()
+            .map(|signature| PathSignature {
+                store_path: path_str.to_owned(),
+                signature: signature.clone(),
+            })
+            .collect();
+        diesel::insert_or_ignore_into(signatures::table)
+            .values(&new_signatures)
+            .execute(self.inner.borrow_mut().deref_mut())?;
+
+        Ok(())
+    }
+
+    /// Paths that reference `path`, not counting itself
+    pub fn referrers<P: AsRef<Path>>(&self, path: P) -> Result<Vec<String>> {
+        let path_str = path.as_ref().to_str().unwrap();
+
+        let result = refs::table
+            .filter(refs::reference.is(path_str))
+            .filter(refs::referrer.is_not(path_str))
+            .select(refs::referrer)
+            .order(refs::referrer)
+            .load(self.inner.borrow_mut().deref_mut())?;
+
+        Ok(result)
+    }
+
+    /// Every path that reaches `paths` through references, including themselves
+    pub fn referrers_closure<I>(&self, paths: I) -> Result<BTreeSet<String>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<Path>,
+    {
+        let mut result = BTreeSet::new();
+        let mut pending: Vec<String> = paths
+            .into_iter()
+            .map(|p| p.as_ref().to_str().unwrap().to_owned())
+            .collect();
+
+        while let Some(path) = pending.pop() {
+            if result.insert(path.clone()) {
+                pending.extend(self.referrers(&path)?);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Sort `paths` so that every path comes before the paths it references
+    pub fn deletion_order(&self, mut paths: BTreeSet<String>) -> Result<Vec<String>> {
+        let mut result = Vec::with_capacity(paths.len());
+
+        while !paths.is_empty() {
+            let mut ready = Vec::new();
+            for path in &paths {
+                let referrers = self.referrers(path)?;
+                if !referrers.iter().any(|r| paths.contains(r)) {
+                    ready.push(path.clone());
+                }
+            }
+
+            if ready.is_empty() {
+                bail!("Reference cycle between paths: {:?}", paths);
+            }
+
+            for path in ready {
+                paths.remove(&path);
+                result.push(path);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Every path reachable from `paths` through references, including themselves
+    pub fn closure<I>(&self, paths: I) -> Result<BTreeSet<String>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<Path>,
+    {
+        let mut result = BTreeSet::new();
+        let mut pending: Vec<String> = paths
+            .into_iter()
+            .map(|p| p.as_ref().to_str().unwrap().to_owned())
+            .collect();
+
+        while let Some(path) = pending.pop() {
+            if result.insert(path.clone()) {
+                pending.extend(self.references(&path)?);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// Optimise a newly registered store path, if enabled in the config
+fn auto_optimise<P: AsRef<Path>>(path: P) {
+    if crate::config::get().auto_optimise {
+        let mut report = crate::optimise::OptimiseReport::default();
+        if let Err(err) = crate::optimise::optimise_path(&path, &mut report) {
+            warn!(?err, "Failed to optimise");
+        }
+        debug!(?report, "Optimised");
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the epoch")
+        .as_secs() as i64
+}
+
+/// Turn a store path from user input into the path it's registered as, accepting the path of its
+/// files in the physical store too
+pub fn normalize_store_path<P: AsRef<Path> + std::fmt::Debug>(path: P) -> PathBuf {
+    crate::layout::get().logical(fix_dir_trailing_slash(path))
+}
+
+/// The store path of `path`, following it if it's a link into the store, like an out link or a
+/// profile generation
+pub fn resolve_store_path(path: &Path) -> Result<String> {
+    let path = match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_symlink() => {
+            std::fs::canonicalize(path).wrap_err(format!("Following {:?}", path))?
+        }
+        _ => path.to_owned(),
+    };
+
+    let path = normalize_store_path(&path);
+    let result = MiqResult::from_store_path(&path)
+        .ok_or_else(|| eyre!("{:?} is not a store path", path))
+        .suggestion("Pass a path under /miq/store, or a link to one")?;
+    Ok(result.store_path().to_string_lossy().into_owned())
+}
+
+/// Write a file into the store as the path of `result`, and register it
+#[cfg(test)]
+pub fn add_test_path(conn: &mut DbConnection, result: &MiqResult, contents: &str) -> String {
+    let path = result.store_path();
+    std::fs::write(crate::layout::get().physical(&path), contents).unwrap();
+    conn.add(&path).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+fn parse_remove(args: &[&str]) -> clap::error::Result<RemoveArgs> {
+    use clap::{Args, FromArgMatches};
+    let matches = RemoveArgs::augment_args(clap::Command::new("rm")).try_get_matches_from(args)?;
+    RemoveArgs::from_arg_matches(&matches)
+}
+
+#[test]
+fn test_remove_conflicts() {
+    for selector in [
+        &["--all-packages"][..],
+        &["--type", "fetch"],
+        &["--name", "gcc-*"],
+        &["--older-than", "1day"],
+        &["--orphaned"],
+    ] {
+        let args = [&["rm", "--all"][..], selector].concat();
+        let err = parse_remove(&args).map(|_| ()).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            clap::error::ErrorKind::ArgumentConflict,
+            "{:?}",
+            args
+        );
+    }
+    assert!(parse_remove(&["rm", "--name", "gcc-*", "--orphaned"]).is_ok());
+    assert!(parse_remove(&["rm", "--keep", "foo.toml"]).is_err());
+}
+
+#[test]
+fn test_remove_matches() {
+    use crate::schema_eval::{Fetch, Package};
+
+    let _store = crate::layout::test_store();
+    let package = Unit::PackageUnit(Package {
+        result: MiqResult::create("gcc-12", &"package"),
+        name: String::from("gcc"),
+        ..Default::default()
+    });
+    let fetch = Unit::FetchUnit(Fetch {
+        result: MiqResult::create("gcc-12.tar.gz", &"fetch"),
+        name: String::from("gcc-12.tar.gz"),
+        ..Default::default()
+    });
+    package.write_to_disk().unwrap();
+    fetch.write_to_disk().unwrap();
+    // Not built from a current unit
+    let orphan = MiqResult::create("zlib-1.3", &"orphan");
+    let current = BTreeSet::from([package.result().clone(), fetch.result().clone()]);
+
+    let now = unix_now();
+    let paths = [
+        (package.result(), now - 3600),
+        (fetch.result(), now),
+        (&orphan, now),
+    ]
+    .map(|(result, time)| StorePath {
+        store_path: result.store_path().to_string_lossy().into_owned(),
+        registered_at: time,
+        archive_hash: None,
+        archive_size: None,
+    });
+
+    for (args, expected) in [
+        (&["--name", "gcc-*"][..], [true, true, false]),
+        (&["--type", "package"], [true, false, false]),
+        (&["--all-packages"], [true, false, false]),
+        (&["--type", "fetch"], [false, true, false]),
+        (&["--orphaned"], [false, false, true]),
+        (&["--older-than", "30min"], [true, false, false]),
+        (
+            &["--name", "gcc-*", "--type", "fetch"],
+            [false, true, false],
+        ),
+    ] {
+        let remove = parse_remove(&[&["rm"][..], args].concat()).unwrap();
+        let matched = paths
+            .each_ref()
+            .map(|path| remove.matches(path, now, &current).unwrap());
+        assert_eq!(matched, expected, "{:?}", args);
+    }
+}
+
+#[test]
+fn test_remove_dry_run() {
+    let _store = crate::layout::test_store();
+    let mut conn = DbConnection::new().unwrap();
+    let path = add_test_path(&mut conn, &MiqResult::create("dry-run", &"dry-run"), "x");
+
+    parse_remove(&["rm", "--name", "dry-run", "--dry-run"])
+        .unwrap()
+        .run(&mut conn)
+        .unwrap();
+    assert!(conn.is_db_path(&path).unwrap());
+    assert!(crate::layout::get().physical(&path).exists());
+
+    parse_remove(&["rm", "--name", "dry-run"])
+        .unwrap()
+        .run(&mut conn)
+        .unwrap();
+    assert!(!conn.is_db_path(&path).unwrap());
+    assert!(!crate::layout::get().physical(&path).exists());
+}
+
+#[test]
+fn test_remove_rooted() {
+    let _store = crate::layout::test_store();
+    let mut conn = DbConnection::new().unwrap();
+    let path = add_test_path(&mut conn, &MiqResult::create("rooted", &"rooted"), "x");
+    let root = crate::layout::get().gcroots_dir().join("rooted");
+    std::os::unix::fs::symlink(crate::layout::get().physical(&path), &root).unwrap();
+
+    parse_remove(&["rm", "--orphaned"])
+        .unwrap()
+        .run(&mut conn)
+        .unwrap();
+    assert!(conn.is_db_path(&path).unwrap());
+
+    let err = parse_remove(&["rm", &path])
+        .unwrap()
+        .run(&mut conn)
+        .unwrap_err();
+    assert!(err.to_string().contains("GC root"), "{}", err);
+    assert!(conn.is_db_path(&path).unwrap());
+
+    parse_remove(&["rm", &path, "--force"])
+        .unwrap()
+        .run(&mut conn)
+        .unwrap();
+    assert!(!conn.is_db_path(&path).unwrap());
+    std::fs::remove_file(root).unwrap();
+}
+
+#[test]
+fn test_remove_orphaned() {
+    use crate::schema_eval::{Fetch, Package};
+
+    let _store = crate::layout::test_store();
+    let mut conn = DbConnection::new().unwrap();
+    let fetch_unit = |name: &str| {
+        let unit = Unit::FetchUnit(Fetch {
+            result: MiqResult::create(name, &name),
+            name: name.to_owned(),
+            ..Default::default()
+        });
+        unit.write_to_disk().unwrap();
+        unit
+    };
+    // Only needed to build the package, so the package path doesn't reference it
+    let source = fetch_unit("orphaned-source.tar.gz");
+    let package = Unit::PackageUnit(Package {
+        result: MiqResult::create("orphaned-package", &"package"),
+        name: String::from("orphaned-package"),
+        deps: BTreeSet::from([source.result().clone()]),
+        ..Default::default()
+    });
+    package.write_to_disk().unwrap();
+    let old = fetch_unit("orphaned-old.tar.gz");
+    let kept = fetch_unit("orphaned-kept.tar.gz");
+
+    let [source, package, old, kept] =
+        [&source, &package, &old, &kept].map(|unit| add_test_path(&mut conn, unit.result(), "x"));
+    let root = crate::layout::get().gcroots_dir().join("orphaned");
+    std::os::unix::fs::symlink(crate::layout::get().physical(&package), &root).unwrap();
+
+    let keep = MiqResult::from_store_path(&kept).unwrap().eval_path();
+    parse_remove(&["rm", "--orphaned", "--keep", keep.to_str().unwrap()])
+        .unwrap()
+        .run(&mut conn)
+        .unwrap();
+    // Its unit is still in the eval directory, but nothing current was built from it
+    assert!(!conn.is_db_path(&old).unwrap());
+    for path in [&source, &package, &kept] {
+        assert!(conn.is_db_path(path).unwrap(), "{}", path);
+    }
+    std::fs::remove_file(root).unwrap();
 }
 
 /// Remove trailing slashes from directories (coming from user input)
-fn fix_dir_trailing_slash<P: AsRef<Path> + std::fmt::Debug>(path: P) -> PathBuf {
+pub fn fix_dir_trailing_slash<P: AsRef<Path> + std::fmt::Debug>(path: P) -> PathBuf {
     let base = &mut PathBuf::from("/");
 
     for comp in path.as_ref().components() {
@@ -0,0 +1,301 @@
+//! Comparing the closures of two store paths, to see what a rebuild changed.
+//!
+//! The members of each closure are grouped by package name, so that `libc-1.0-<hash>` and
+//! `libc-1.1-<hash>` show up as a version change rather than as a removal and an addition.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::ensure;
+use color_eyre::Result;
+use indicatif::HumanBytes;
+use serde::Serialize;
+
+use crate::db::DbConnection;
+use crate::eval::MiqResult;
+use crate::schema_eval::Unit;
+
+#[derive(Debug, clap::Args)]
+/// Show what changed between the closures of two store paths
+pub struct Args {
+    #[arg(value_hint = clap::ValueHint::AnyPath)]
+    /// Store path, or a link to one like an out link or a profile generation
+    before: PathBuf,
+
+    #[arg(value_hint = clap::ValueHint::AnyPath)]
+    /// Store path to compare against the first one
+    after: PathBuf,
+
+    /// Print the changes as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Members of a closure with the same package name
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Group {
+    paths: BTreeSet<String>,
+    /// Versions of the paths that have one
+    versions: BTreeSet<String>,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ChangeKind {
+    Added,
+    Removed,
+    /// The versions changed
+    Version,
+    /// The versions are the same, but the paths are different, like after a dependency changed
+    Rebuilt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Change {
+    name: String,
+    kind: ChangeKind,
+    /// Versions before and after, see `before_paths` for the paths without one
+    before: BTreeSet<String>,
+    after: BTreeSet<String>,
+    before_paths: BTreeSet<String>,
+    after_paths: BTreeSet<String>,
+    /// Bytes gained, or lost if negative
+    size_delta: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    before_size: u64,
+    after_size: u64,
+    changes: Vec<Change>,
+}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        let conn = DbConnection::new()?;
+        let before = closure_groups(&conn, &crate::db::resolve_store_path(&self.before)?)?;
+        let after = closure_groups(&conn, &crate::db::resolve_store_path(&self.after)?)?;
+
+        let report = Report {
+            before_size: before.values().map(|g| g.size).sum(),
+            after_size: after.values().map(|g| g.size).sum(),
+            changes: diff(&before, &after),
+        };
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
+
+        for change in &report.changes {
+            println!("{}", format_change(change));
+        }
+        println!(
+            "Closure size: {} → {} ({})",
+            HumanBytes(report.before_size),
+            HumanBytes(report.after_size),
+            format_delta(report.after_size as i64 - report.before_size as i64)
+        );
+
+        Ok(())
+    }
+}
+
+/// Name and version of a store path, from its unit if it's a package, or else from its name like
+/// `name-1.2.3`
+fn name_version(result: &MiqResult) -> (String, Option<String>) {
+    if let Ok(Unit::PackageUnit(package)) = Unit::from_result(result) {
+        return (package.name, package.version);
+    }
+    split_version(result.name())
+}
+
+/// Split `name-1.2.3` at the first dash followed by a digit
+fn split_version(name: &str) -> (String, Option<String>) {
+    let split = name
+        .match_indices('-')
+        .map(|(index, _)| index)
+        .find(|&index| name[index + 1..].starts_with(|c: char| c.is_ascii_digit()));
+
+    match split {
+        Some(index) => (name[..index].to_owned(), Some(name[index + 1..].to_owned())),
+        None => (name.to_owned(), None),
+    }
+}
+
+fn closure_groups(conn: &DbConnection, path: &str) -> Result<BTreeMap<String, Group>> {
+    ensure!(
+        conn.path_info(path)?.is_some(),
+        "{:?} is not a registered path",
+        path
+    );
+    let mut groups: BTreeMap<String, Group> = BTreeMap::new();
+
+    for member in conn.closure([path])? {
+        let Some(result) = MiqResult::from_store_path(&member) else {
+            continue;
+        };
+        let (name, version) = name_version(&result);
+        let size = match conn.path_info(&member)? {
+            Some(info) => info.size()?,
+            None => 0,
+        };
+
+        let group = groups.entry(name).or_default();
+        group.versions.extend(version);
+        group.size += size;
+        group.paths.insert(member);
+    }
+
+    Ok(groups)
+}
+
+fn diff(before: &BTreeMap<String, Group>, after: &BTreeMap<String, Group>) -> Vec<Change> {
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    let empty = Group::default();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old = before.get(name);
+            let new = after.get(name);
+            let kind = match (old, new) {
+                (None, _) => ChangeKind::Added,
+                (_, None) => ChangeKind::Removed,
+                (Some(old), Some(new)) if old.versions != new.versions => ChangeKind::Version,
+                (Some(old), Some(new)) if old.paths != new.paths => ChangeKind::Rebuilt,
+                _ => return None,
+            };
+
+            let old = old.unwrap_or(&empty);
+            let new = new.unwrap_or(&empty);
+            Some(Change {
+                name: name.clone(),
+                kind,
+                before: old.versions.clone(),
+                after: new.versions.clone(),
+                before_paths: old.paths.clone(),
+                after_paths: new.paths.clone(),
+                size_delta: new.size as i64 - old.size as i64,
+            })
+        })
+        .collect()
+}
+
+fn format_versions(versions: &BTreeSet<String>) -> String {
+    match versions.len() {
+        0 => String::from("∅"),
+        _ => versions.iter().cloned().collect::<Vec<_>>().join(", "),
+    }
+}
+
+fn format_delta(delta: i64) -> String {
+    let sign = if delta < 0 { '-' } else { '+' };
+    format!("{}{}", sign, HumanBytes(delta.unsigned_abs()))
+}
+
+/// The versions of the paths that were added or removed, or their names if they have none
+fn format_side(versions: &BTreeSet<String>, paths: &BTreeSet<String>) -> String {
+    if !versions.is_empty() {
+        return format_versions(versions);
+    }
+    paths
+        .iter()
+        .map(|p| {
+            Path::new(p)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn format_change(change: &Change) -> String {
+    let what = match change.kind {
+        ChangeKind::Version => format!(
+            "{} → {}",
+            format_versions(&change.before),
+            format_versions(&change.after)
+        ),
+        ChangeKind::Added => format_side(&change.after, &change.after_paths),
+        ChangeKind::Removed => format_side(&change.before, &change.before_paths),
+        ChangeKind::Rebuilt => String::from("rebuilt"),
+    };
+
+    let tag = match change.kind {
+        ChangeKind::Added => " (added)",
+        ChangeKind::Removed => " (removed)",
+        ChangeKind::Version | ChangeKind::Rebuilt => "",
+    };
+
+    format!(
+        "{}: {}, {}{}",
+        change.name,
+        what,
+        format_delta(change.size_delta),
+        tag
+    )
+}
+
+#[test]
+fn test_split_version() {
+    assert_eq!(
+        split_version("musl-1.2.3"),
+        (String::from("musl"), Some(String::from("1.2.3")))
+    );
+    assert_eq!(
+        split_version("bootstrap-tools.tar.xz"),
+        (String::from("bootstrap-tools.tar.xz"), None)
+    );
+    assert_eq!(
+        split_version("gcc-wrapper-12"),
+        (String::from("gcc-wrapper"), Some(String::from("12")))
+    );
+}
+
+#[test]
+fn test_diff() {
+    let group = |paths: &[&str], versions: &[&str], size| Group {
+        paths: paths.iter().map(|p| p.to_string()).collect(),
+        versions: versions.iter().map(|v| v.to_string()).collect(),
+        size,
+    };
+
+    let before = BTreeMap::from([
+        (String::from("libc"), group(&["libc-1.0-a"], &["1.0"], 100)),
+        (String::from("cc"), group(&["cc-a"], &[], 50)),
+        (String::from("zlib"), group(&["zlib-1.3-a"], &["1.3"], 10)),
+        (String::from("old"), group(&["/miq/store/old-a"], &[], 5)),
+    ]);
+    let after = BTreeMap::from([
+        (String::from("libc"), group(&["libc-1.1-b"], &["1.1"], 120)),
+        (String::from("cc"), group(&["cc-b"], &[], 50)),
+        (String::from("zlib"), group(&["zlib-1.3-a"], &["1.3"], 10)),
+        (String::from("new"), group(&["new-2-a"], &["2"], 7)),
+    ]);
+
+    let changes = diff(&before, &after);
+    let kinds: Vec<(&str, ChangeKind, i64)> = changes
+        .iter()
+        .map(|c| (c.name.as_str(), c.kind, c.size_delta))
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            ("cc", ChangeKind::Rebuilt, 0),
+            ("libc", ChangeKind::Version, 20),
+            ("new", ChangeKind::Added, 7),
+            ("old", ChangeKind::Removed, -5),
+        ]
+    );
+    assert_eq!(format_change(&changes[1]), "libc: 1.0 → 1.1, +20B");
+    assert_eq!(format_change(&changes[2]), "new: 2, +7B (added)");
+    assert_eq!(format_change(&changes[3]), "old: old-a, -5B (removed)");
+    assert_eq!(
+        changes[3].before_paths,
+        BTreeSet::from([String::from("/miq/store/old-a")])
+    );
+    assert!(changes[3].after_paths.is_empty());
+}
@@ -0,0 +1,140 @@
+//! Keeping free space on the disks that builds write to.
+//!
+//! With `min-free` set in the config, the build scheduler checks the free space of the store and
+//! of the temporary directory before starting each job, and every [CHECK_INTERVAL] while jobs run.
+//! Below `min-free`, unrooted paths are garbage collected until `max-free` is free. If that's not
+//! enough, new jobs wait for the running ones to finish, and the build fails once none are left.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Help, Result};
+use indicatif::HumanBytes;
+use tracing::{debug, info, warn};
+
+use crate::db::DbConnection;
+
+/// How often the free space is checked while jobs run
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bytes available to unprivileged users in the filesystem of `path`
+pub fn free_space<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+    let stat = nix::sys::statvfs::statvfs(path)
+        .wrap_err(format!("Reading the free space of {:?}", path))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+#[derive(Debug)]
+pub struct DiskGuard {
+    min_free: u64,
+    max_free: Option<u64>,
+    dirs: Vec<PathBuf>,
+}
+
+impl DiskGuard {
+    /// The guard for the thresholds of the config, if `min-free` is set
+    pub fn from_config() -> Option<Self> {
+        let config = crate::config::get();
+        let guard = Self {
+            min_free: config.min_free?,
+            max_free: config.max_free,
+            dirs: vec![crate::layout::get().store_dir(), std::env::temp_dir()],
+        };
+        debug!(?guard);
+        Some(guard)
+    }
+
+    /// The directory with the least free space, and how much it has
+    fn lowest(&self) -> Result<(&Path, u64)> {
+        let mut lowest = None;
+        for dir in &self.dirs {
+            let free = free_space(dir)?;
+            if lowest.is_none_or(|(_, f)| free < f) {
+                lowest = Some((dir.as_path(), free));
+            }
+        }
+        Ok(lowest.expect("The guard checks at least one directory"))
+    }
+
+    /// Collect garbage if a directory is below `min-free`, returning the lowest free space after
+    pub fn collect_if_low(&self, conn: &Mutex<DbConnection>) -> Result<(&Path, u64)> {
+        let (dir, free) = self.lowest()?;
+        if free >= self.min_free {
+            return Ok((dir, free));
+        }
+
+        warn!(
+            ?dir,
+            free = %HumanBytes(free),
+            min_free = %HumanBytes(self.min_free),
+            "Low on disk space, collecting garbage"
+        );
+        let max_freed = self
+            .max_free
+            .map(|max| max.max(self.min_free).saturating_sub(free));
+        let report = crate::gc::collect_garbage_shared(&mut conn.lock().unwrap(), max_freed)?;
+        info!(
+            deleted = report.deleted.len(),
+            freed = %HumanBytes(report.freed),
+            "Collected garbage"
+        );
+
+        self.lowest()
+    }
+
+    /// Whether a new job can start. If collecting garbage doesn't bring the free space back over
+    /// `min-free`, it waits for the `running` jobs to finish, or fails if there are none.
+    pub fn allow_job(&self, conn: &Mutex<DbConnection>, running: bool) -> Result<bool> {
+        let (dir, free) = self.collect_if_low(conn)?;
+        if free >= self.min_free {
+            return Ok(true);
+        }
+
+        if running {
+            debug!(?dir, free = %HumanBytes(free), "Pausing new jobs");
+            return Ok(false);
+        }
+
+        Err(eyre!(
+            "Only {} free in {:?}, below min-free ({}), and garbage collection couldn't free more",
+            HumanBytes(free),
+            dir,
+            HumanBytes(self.min_free)
+        )
+        .suggestion("Remove GC roots that are no longer needed, or lower min-free in the config"))
+    }
+}
+
+#[test]
+fn test_free_space() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(free_space(dir.path()).unwrap() > 0);
+    assert!(free_space(dir.path().join("missing")).is_err());
+}
+
+#[test]
+fn test_allow_job() {
+    let _store = crate::layout::test_store();
+    let conn = Mutex::new(DbConnection::new().unwrap());
+    let dir = tempfile::tempdir().unwrap();
+
+    let guard = DiskGuard {
+        min_free: 0,
+        max_free: None,
+        dirs: vec![dir.path().to_owned()],
+    };
+    assert!(guard.allow_job(&conn, true).unwrap());
+    assert!(guard.allow_job(&conn, false).unwrap());
+
+    // No disk has this much free, whatever garbage is collected
+    let guard = DiskGuard {
+        min_free: u64::MAX,
+        ..guard
+    };
+    assert!(!guard.allow_job(&conn, true).unwrap());
+    let err = guard.allow_job(&conn, false).unwrap_err().to_string();
+    assert!(err.contains("below min-free"), "{}", err);
+}
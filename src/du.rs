@@ -0,0 +1,251 @@
+//! Disk usage of store paths and of their closures.
+//!
+//! Sizes are the ones recorded in the database when the paths were registered, so the store is
+//! only read for paths registered before sizes were recorded, which are measured on disk instead.
+//! The closure size of a path counts every path it needs once, even if it's reachable through
+//! several references.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use indicatif::HumanBytes;
+
+use crate::db::DbConnection;
+
+#[derive(Debug, clap::Args)]
+/// Show the size of store paths and of their closures
+pub struct Args {
+    #[arg(value_hint = clap::ValueHint::AnyPath)]
+    /// Store paths, or links to them. Defaults to every registered path
+    paths: Vec<PathBuf>,
+
+    /// How to order the paths. Sizes go from the biggest
+    #[arg(long, value_enum, default_value_t = SortKey::Closure)]
+    sort: SortKey,
+
+    /// Only show the first N paths
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Show how much of the closure of each path comes from each of its references
+    #[arg(long)]
+    tree: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SortKey {
+    /// Size of the path itself
+    Size,
+    /// Size of the path and everything it references
+    Closure,
+    /// Name of the path, alphabetically
+    Name,
+}
+
+/// Sizes and references of every registered path, loaded once from the database
+#[derive(Debug, Default)]
+struct Graph {
+    sizes: BTreeMap<String, u64>,
+    references: BTreeMap<String, Vec<String>>,
+}
+
+/// A direct reference of a path, and the part of the closure of the path it accounts for
+#[derive(Debug, PartialEq, Eq)]
+struct Attribution {
+    path: String,
+    size: u64,
+    closure: u64,
+    /// Bytes that the path only needs through this reference
+    unique: u64,
+}
+
+impl Graph {
+    fn load(conn: &DbConnection) -> Result<Self> {
+        let mut graph = Graph::default();
+        for info in conn.list()? {
+            graph.sizes.insert(info.store_path.clone(), info.size()?);
+        }
+        for r in conn.all_refs()? {
+            if r.referrer != r.reference {
+                graph
+                    .references
+                    .entry(r.referrer)
+                    .or_default()
+                    .push(r.reference);
+            }
+        }
+        Ok(graph)
+    }
+
+    fn size(&self, path: &str) -> u64 {
+        self.sizes.get(path).copied().unwrap_or_default()
+    }
+
+    fn closure<'a, I>(&'a self, roots: I) -> BTreeSet<&'a str>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut result = BTreeSet::new();
+        let mut pending: Vec<&str> = roots.into_iter().collect();
+
+        while let Some(path) = pending.pop() {
+            if result.insert(path) {
+                if let Some(references) = self.references.get(path) {
+                    pending.extend(references.iter().map(String::as_str));
+                }
+            }
+        }
+
+        result
+    }
+
+    fn closure_size(&self, path: &str) -> u64 {
+        self.closure([path]).iter().map(|p| self.size(p)).sum()
+    }
+
+    fn attribute(&self, path: &str) -> Vec<Attribution> {
+        let direct = self.references.get(path).cloned().unwrap_or_default();
+
+        direct
+            .iter()
+            .map(|reference| {
+                let others = direct
+                    .iter()
+                    .filter(|r| *r != reference)
+                    .map(String::as_str);
+                let mut without = self.closure(others);
+                without.insert(path);
+                let with = self.closure([reference.as_str()]);
+                Attribution {
+                    path: reference.clone(),
+                    size: self.size(reference),
+                    closure: with.iter().map(|p| self.size(p)).sum(),
+                    unique: with.difference(&without).map(|p| self.size(p)).sum(),
+                }
+            })
+            .collect()
+    }
+}
+
+fn sort_by<T, F>(items: &mut [T], key: SortKey, fields: F)
+where
+    F: Fn(&T) -> (&str, u64, u64),
+{
+    items.sort_by(|a, b| {
+        let (a_name, a_size, a_closure) = fields(a);
+        let (b_name, b_size, b_closure) = fields(b);
+        match key {
+            SortKey::Size => b_size.cmp(&a_size),
+            SortKey::Closure => b_closure.cmp(&a_closure),
+            SortKey::Name => a_name.cmp(b_name),
+        }
+    });
+}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        let conn = DbConnection::new()?;
+        let graph = Graph::load(&conn)?;
+
+        let mut paths: Vec<(String, u64, u64)> = Vec::new();
+        if self.paths.is_empty() {
+            for path in graph.sizes.keys() {
+                paths.push((path.clone(), graph.size(path), graph.closure_size(path)));
+            }
+        } else {
+            for path in &self.paths {
+                let path = crate::db::resolve_store_path(path)?;
+                if !graph.sizes.contains_key(&path) {
+                    bail!("{:?} is not a registered path", path);
+                }
+                let (size, closure) = (graph.size(&path), graph.closure_size(&path));
+                paths.push((path, size, closure));
+            }
+        }
+
+        sort_by(&mut paths, self.sort, |(path, size, closure)| {
+            (path, *size, *closure)
+        });
+        paths.truncate(self.top.unwrap_or(paths.len()));
+
+        if !self.tree {
+            println!("{:>12} {:>12}  PATH", "SIZE", "CLOSURE");
+        }
+
+        for (path, size, closure) in &paths {
+            if !self.tree {
+                println!(
+                    "{:>12} {:>12}  {}",
+                    HumanBytes(*size).to_string(),
+                    HumanBytes(*closure).to_string(),
+                    path
+                );
+                continue;
+            }
+
+            println!(
+                "{}: {} itself, {} closure",
+                path,
+                HumanBytes(*size),
+                HumanBytes(*closure)
+            );
+            let mut attributions = graph.attribute(path);
+            sort_by(&mut attributions, self.sort, |a| {
+                (&a.path, a.size, a.closure)
+            });
+            for (index, a) in attributions.iter().enumerate() {
+                let branch = if index + 1 == attributions.len() {
+                    "└──"
+                } else {
+                    "├──"
+                };
+                println!(
+                    "  {} {}: {} closure, {} only through it",
+                    branch,
+                    a.path,
+                    HumanBytes(a.closure),
+                    HumanBytes(a.unique)
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_attribute() {
+    // stage1 -> cc -> libc, stage1 -> libc, stage1 -> tools
+    let mut graph = Graph::default();
+    for (path, size) in [("stage1", 1), ("cc", 10), ("libc", 100), ("tools", 1000)] {
+        graph.sizes.insert(path.to_owned(), size);
+    }
+    for (referrer, reference) in [
+        ("stage1", "cc"),
+        ("stage1", "libc"),
+        ("stage1", "tools"),
+        ("cc", "libc"),
+    ] {
+        graph
+            .references
+            .entry(referrer.to_owned())
+            .or_default()
+            .push(reference.to_owned());
+    }
+
+    assert_eq!(graph.closure_size("stage1"), 1111);
+    assert_eq!(graph.closure_size("cc"), 110);
+
+    let attributions = graph.attribute("stage1");
+    let summary: Vec<(&str, u64, u64)> = attributions
+        .iter()
+        .map(|a| (a.path.as_str(), a.closure, a.unique))
+        .collect();
+    // libc is also reachable through cc, so neither of them owns it
+    assert_eq!(
+        summary,
+        [("cc", 110, 10), ("libc", 100, 0), ("tools", 1000, 1000)]
+    );
+}
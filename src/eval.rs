@@ -1,6 +1,4 @@
-use std::fs::OpenOptions;
-use std::hash::Hash;
-use std::io::Write;
+use std::collections::BTreeSet;
 use std::path::Path;
 use std::str::FromStr;
 
@@ -66,7 +64,9 @@ pub enum UnitRef {
 
 impl RefToUnit for PathBuf {
     fn ref_to_unit(&self) -> Result<Unit> {
-        let file_contents = std::fs::read_to_string(self)?;
+        let path = crate::layout::get().physical(self);
+        let file_contents =
+            std::fs::read_to_string(&path).wrap_err(format!("Reading unit {:?}", path))?;
         let deserialized = toml::from_str(&file_contents)?;
         Ok(deserialized)
     }
@@ -76,8 +76,11 @@ impl FromStr for UnitRef {
     type Err = Report;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.starts_with("/miq/eval") {
-            return Ok(Self::Serialized(PathBuf::from(s)));
+        // Parsed with the arguments, before the layout is known, so eval files under the default
+        // root are only mapped to the eval directory when they are read. They are absolute from
+        // here on, as the daemon doesn't share the working directory of the client.
+        if Path::new(s).extension().is_some_and(|ext| ext == "toml") {
+            return Ok(Self::Serialized(std::path::absolute(s)?));
         };
 
         if s.contains(".lua") {
@@ -90,6 +93,80 @@ impl FromStr for UnitRef {
     }
 }
 
+impl UnitRef {
+    /// Like [RefToUnit::ref_to_unit], for a unitref that the user `reader` sent to the daemon. Lua
+    /// files are evaluated in a sandbox, and only units from the eval directory are read.
+    pub fn ref_to_unit_sandboxed(&self, reader: nix::unistd::Uid) -> Result<Unit> {
+        match self {
+            UnitRef::Serialized(path) => {
+                let layout = crate::layout::get();
+                let eval_dir = layout.eval_dir().canonicalize()?;
+                let path = layout
+                    .physical(path)
+                    .canonicalize()
+                    .wrap_err(format!("Reading unit {:?}", path))?;
+                ensure!(
+                    path.starts_with(&eval_dir),
+                    "{:?} is not in the eval directory {:?}",
+                    path,
+                    eval_dir
+                );
+                path.ref_to_unit()
+            }
+            UnitRef::Lua(luaref) => luaref.ref_to_unit_sandboxed(reader),
+        }
+    }
+}
+
+#[test]
+fn test_unitref_paths() {
+    let _store = crate::layout::test_store();
+    let unit = Unit::FetchUnit(schema_eval::Fetch {
+        result: MiqResult::create("unitref", &"unitref"),
+        name: "unitref".to_owned(),
+        ..Default::default()
+    });
+    unit.write_to_disk().unwrap();
+
+    let relative: UnitRef = "unitref.toml".parse().unwrap();
+    let cwd = std::env::current_dir().unwrap();
+    assert!(matches!(relative, UnitRef::Serialized(path) if path == cwd.join("unitref.toml")));
+
+    let logical = format!("/miq/eval/{}.toml", unit.result().as_str());
+    let logical: UnitRef = logical.parse().unwrap();
+    assert_eq!(logical.ref_to_unit().unwrap(), unit);
+    assert_eq!(
+        logical
+            .ref_to_unit_sandboxed(nix::unistd::geteuid())
+            .unwrap(),
+        unit
+    );
+}
+
+impl std::fmt::Display for UnitRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnitRef::Serialized(path) => write!(f, "{}", path.to_string_lossy()),
+            UnitRef::Lua(luaref) => write!(f, "{}", luaref),
+        }
+    }
+}
+
+/// Unitrefs are sent to the daemon as strings, which are absolute after parsing
+impl Serialize for UnitRef {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for UnitRef {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 impl crate::Main for Args {
     fn main(&self) -> Result<()> {
         let root_unit = self.unit_ref.ref_to_unit()?;
@@ -125,16 +202,12 @@ fn graphviz_unit_format(unit: &Unit, use_paths: bool) -> String {
     } else {
         match unit {
             Unit::PackageUnit(inner) => {
-                format!(
-                    "{}",
-                    inner.name,
-                    // FIXME
-                    // inner.version.unwrap_or(String::new())
-                )
-            }
-            Unit::FetchUnit(inner) => {
-                format!("{}", inner.name)
+                // FIXME
+                // inner.version.unwrap_or(String::new())
+                inner.name.clone()
             }
+            Unit::FetchUnit(inner) => inner.name.clone(),
+            Unit::EnvUnit(inner) => inner.name.clone(),
         }
     };
 
@@ -145,6 +218,9 @@ fn graphviz_unit_format(unit: &Unit, use_paths: bool) -> String {
         Unit::FetchUnit(_) => {
             format!("label = \"{}\", shape=box, color=gray70 ", pretty_name)
         }
+        Unit::EnvUnit(_) => {
+            format!("label = \"{}\", shape=folder ", pretty_name)
+        }
     }
 }
 
@@ -157,11 +233,7 @@ pub fn dag(input: Unit) -> Result<(UnitDag, NodeIndex)> {
 
     let mut cycle = 0;
 
-    while dag
-        .raw_nodes()
-        .iter()
-        .any(|node| node.weight.visited == false)
-    {
+    while dag.raw_nodes().iter().any(|node| !node.weight.visited) {
         ensure!(cycle <= MAX_DAG_CYCLES, "Maximum dag eval cycles reached");
         cycle += 1;
 
@@ -187,38 +259,31 @@ fn add_children_recursive(dag: &mut UnitNodeDag, index: NodeIndex) -> Result<()>
     let _span = span!(tracing::Level::TRACE, "adding children", ?unit);
     let _enter = _span.enter();
 
-    match unit {
-        Unit::FetchUnit(_) => {
-            trace!("Fetch unit, no children to add");
-        }
-        Unit::PackageUnit(package) => {
-            for dep in &package.deps {
-                let dep_unit = Unit::from_result(dep)?;
-
-                trace!(?dep, "=> adding dep");
-
-                let maybe_child_index: Option<NodeIndex> = dag
-                    .graph()
-                    .node_indices()
-                    .find(|&index| &dag[index].inner == &dep_unit);
-
-                let child_index: NodeIndex = match maybe_child_index {
-                    None => {
-                        let dep_unit_node = Weight::new(dep_unit);
-                        let (_, child_index) = dag.add_child(index, (), dep_unit_node);
-                        child_index
-                    }
-                    Some(child_index) => {
-                        if !visited {
-                            dag.add_edge(index, child_index, ())?;
-                        }
-                        child_index
-                    }
-                };
-
-                add_children_recursive(dag, child_index)?;
+    for dep in &unit.deps() {
+        let dep_unit = Unit::from_result(dep)?;
+
+        trace!(?dep, "=> adding dep");
+
+        let maybe_child_index: Option<NodeIndex> = dag
+            .graph()
+            .node_indices()
+            .find(|&index| dag[index].inner == dep_unit);
+
+        let child_index: NodeIndex = match maybe_child_index {
+            None => {
+                let dep_unit_node = Weight::new(dep_unit);
+                let (_, child_index) = dag.add_child(index, (), dep_unit_node);
+                child_index
             }
-        }
+            Some(child_index) => {
+                if !visited {
+                    dag.add_edge(index, child_index, ())?;
+                }
+                child_index
+            }
+        };
+
+        add_children_recursive(dag, child_index)?;
     }
 
     dag[index].visited = true;
@@ -244,20 +309,53 @@ fn add_children_recursive(dag: &mut UnitNodeDag, index: NodeIndex) -> Result<()>
 pub struct MiqResult(String);
 
 impl MiqResult {
-    pub fn create<H: Hash>(name: &str, hashable: &H) -> MiqResult {
-        let mut hasher = fnv::FnvHasher::default();
-        hashable.hash(&mut hasher);
-        let hash_result = std::hash::Hasher::finish(&hasher);
-        let hash_string = format!("{:x}", hash_result);
+    /// Derive a result from the canonical serialization of some input, see [`crate::hash`]
+    pub fn create<S: Serialize>(name: &str, hashable: &S) -> MiqResult {
+        let hash_string =
+            crate::hash::unit_hash(name, hashable).expect("Unit inputs must serialize to JSON");
         MiqResult(format!("{}-{}", name, hash_string))
     }
 }
 
+impl MiqResult {
+    /// The hash at the end of the result, which is what references are scanned for
+    pub fn hash_part(&self) -> &str {
+        let (_, hash) = self.0.rsplit_once('-').unwrap_or(("", &self.0));
+        hash
+    }
+
+    /// The human readable part of the result, without the hash
+    pub fn name(&self) -> &str {
+        let (name, _) = self.0.rsplit_once('-').unwrap_or((&self.0, ""));
+        name
+    }
+
+    /// Recover the result from a store path
+    pub fn from_store_path<P: AsRef<Path>>(path: P) -> Option<MiqResult> {
+        let path = path.as_ref();
+        if path.parent()? != Path::new(crate::hash::HASH_STORE_DIR) {
+            return None;
+        }
+        let name = path.file_name()?.to_str()?;
+        Some(MiqResult(name.to_owned()))
+    }
+}
+
 impl Unit {
-    pub fn result<'s>(&'s self) -> &'s MiqResult {
+    pub fn result(&self) -> &MiqResult {
         match self {
             Unit::PackageUnit(inner) => &inner.result,
             Unit::FetchUnit(inner) => &inner.result,
+            Unit::EnvUnit(inner) => &inner.result,
+        }
+    }
+
+    /// Results of the units that must be in the store before this one is built
+    pub fn deps(&self) -> Vec<MiqResult> {
+        match self {
+            Unit::PackageUnit(inner) => inner.deps.iter().cloned().collect(),
+            Unit::FetchUnit(_) => Vec::new(),
+            Unit::EnvUnit(inner) => inner.paths.clone(),
         }
     }
 }
@@ -265,21 +363,37 @@ impl Unit {
 impl Unit {
     pub fn from_result(result: &MiqResult) -> Result<Self> {
         let path = result.eval_path();
-        let raw_text = std::fs::read_to_string(&path.as_path())
+        let raw_text = std::fs::read_to_string(path.as_path())
             .wrap_err(format!("Reading eval path {:?}", path))?;
         let result = toml::from_str(&raw_text)?;
         Ok(result)
     }
 }
 
+/// Results of every unit reachable from `roots` through their dependencies, including the roots
+pub fn deps_closure(roots: &BTreeSet<MiqResult>) -> Result<BTreeSet<MiqResult>> {
+    let mut result = BTreeSet::new();
+    let mut pending: Vec<MiqResult> = roots.iter().cloned().collect();
+
+    while let Some(next) = pending.pop() {
+        if result.contains(&next) {
+            continue;
+        }
+        pending.extend(Unit::from_result(&next)?.deps());
+        result.insert(next);
+    }
+
+    Ok(result)
+}
+
 #[derive(Debug, Clone, Hash, Serialize, Deserialize, PartialEq, JsonSchema, Default, Educe)]
 #[educe(Deref)]
 pub struct MiqEvalPath(PathBuf);
 
 impl MiqResult {
-    pub fn eval_path<'s>(&'s self) -> MiqEvalPath {
-        let path_str = ["/miq/eval/", &self.0, ".toml"].join("");
-        MiqEvalPath(PathBuf::from(path_str))
+    pub fn eval_path(&self) -> MiqEvalPath {
+        let file_name = [&self.0, ".toml"].join("");
+        MiqEvalPath(crate::layout::get().eval_dir().join(file_name))
     }
 }
 
@@ -287,7 +401,11 @@ impl MiqResult {
 fn test_get_evalpath() {
     let input = MiqResult("hello-world-AAAA".into());
     let output = input.eval_path();
-    let output_expected = MiqEvalPath("/miq/eval/hello-world-AAAA.toml".into());
+    let output_expected = MiqEvalPath(
+        crate::layout::get()
+            .eval_dir()
+            .join("hello-world-AAAA.toml"),
+    );
     assert_eq!(output, output_expected);
 }
 
@@ -302,9 +420,9 @@ impl AsRef<Path> for MiqEvalPath {
 pub struct MiqStorePath(PathBuf);
 
 impl MiqResult {
-    pub fn store_path<'s>(&'s self) -> MiqStorePath {
-        let path_str = ["/miq/store/", &self.0].join("");
-        MiqStorePath(PathBuf::from(path_str))
+    /// The logical store path, see [crate::layout] for where its files are
+    pub fn store_path(&self) -> MiqStorePath {
+        MiqStorePath(Path::new(crate::hash::HASH_STORE_DIR).join(&self.0))
     }
 }
 
@@ -316,6 +434,17 @@ fn test_get_storepath() {
     assert_eq!(output, output_expected);
 }
 
+#[test]
+fn test_result_parts() {
+    let input = MiqResult("hello-world-1.0-pfx2ab3qq61c3f8hxipz7qi2s2d16zaj".into());
+    assert_eq!(input.name(), "hello-world-1.0");
+    assert_eq!(input.hash_part(), "pfx2ab3qq61c3f8hxipz7qi2s2d16zaj");
+
+    let parsed = MiqResult::from_store_path(input.store_path());
+    assert_eq!(parsed, Some(input));
+    assert_eq!(MiqResult::from_store_path("/miq/store/foo/bin"), None);
+}
+
 impl AsRef<Path> for MiqStorePath {
     fn as_ref(&self) -> &Path {
         self.0.as_ref()
@@ -324,19 +453,16 @@ impl AsRef<Path> for MiqStorePath {
 
 impl Unit {
     pub fn write_to_disk(&self) -> Result<()> {
-        let header = "#:schema /miq/eval-schema.json";
+        let header = format!(
+            "#:schema {}",
+            crate::layout::get().eval_schema().to_string_lossy()
+        );
         let serialized = toml::to_string_pretty(self)?;
         let eval_path = self.result().eval_path();
 
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&eval_path.as_path())
-            .wrap_err(format!("Opening serialisation file for {:?}", eval_path))?;
-
-        file.write_all(header.as_bytes())?;
-        file.write_all("\n".as_bytes())?;
-        file.write_all(serialized.as_bytes())?;
+        let contents = format!("{}\n{}", header, serialized);
+        crate::build::write_atomic(eval_path.as_path(), contents.as_bytes())
+            .wrap_err(format!("Writing serialisation file for {:?}", eval_path))?;
 
         Ok(())
     }
@@ -0,0 +1,430 @@
+//! Portable archives of store closures, to move paths between machines.
+//!
+//! An export is a zstd stream of strings framed like in [crate::archive]:
+//!
+//! ```text
+//! export = "miq-export-1" { "path" <json metadata> archive } "end"
+//! ```
+//!
+//! Paths come after the paths they reference, and the metadata holds the references, the archive
+//! hash, the signatures and the unit from the eval directory that produced the path. Importing
+//! requires a signature by a trusted key, see [crate::signing], which also covers the unit.
+
+use std::collections::BTreeSet;
+use std::io::{BufReader, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{bail, ensure, eyre, Context};
+use color_eyre::{Help, Result};
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+use tracing::{debug, info};
+
+use crate::archive::HashingWriter;
+use crate::db::DbConnection;
+use crate::eval::MiqResult;
+use crate::gc::TempRoots;
+use crate::hash::Integrity;
+use crate::lock::PathLock;
+use crate::schema_eval::Unit;
+
+const MAGIC: &str = "miq-export-1";
+/// The metadata includes the unit, which can hold a long build script
+const MAX_METADATA_LEN: u64 = 16 * 1024 * 1024;
+
+/// What is needed to register a path on another machine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathMetadata {
+    pub store_path: String,
+    pub archive_hash: Integrity,
+    pub archive_size: u64,
+    pub references: Vec<String>,
+    #[serde(default)]
+    pub signatures: Vec<String>,
+    /// Hash of the unit that produced the path, see [unit_integrity]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eval_hash: Option<Integrity>,
+}
+
+impl PathMetadata {
+    /// Read the metadata of a registered path. Paths registered before archive hashes were
+    /// recorded are hashed now.
+    pub fn read(conn: &DbConnection, path: &str) -> Result<Self> {
+        let info = conn
+            .path_info(path)?
+            .ok_or_else(|| eyre!("{:?} is not a registered path", path))?;
+
+        let (archive_hash, archive_size) = match (info.archive_hash, info.archive_size) {
+            (Some(hash), Some(size)) => (hash.parse()?, size as u64),
+            _ => crate::archive::hash_path(crate::layout::get().physical(path))?,
+        };
+
+        let eval_hash = match read_eval(path)? {
+            Some(eval) => Some(unit_integrity(&parse_eval(path, &eval)?)?),
+            None => None,
+        };
+
+        Ok(Self {
+            store_path: info.store_path,
+            archive_hash,
+            archive_size,
+            references: conn.references(path)?,
+            signatures: conn.signatures(path)?,
+            eval_hash,
+        })
+    }
+
+    /// Like [PathMetadata::read], also signing the path with the keys in the config
+    pub fn read_signed(conn: &DbConnection, path: &str) -> Result<Self> {
+        let mut metadata = Self::read(conn, path)?;
+        crate::signing::sign_with_config(&mut metadata)?;
+        conn.add_signatures(path, &metadata.signatures)?;
+        Ok(metadata)
+    }
+
+    /// Write the archive of the path into `w`, failing if it doesn't match the recorded hash
+    pub fn dump<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut writer = HashingWriter::new(w);
+        crate::archive::dump(crate::layout::get().physical(&self.store_path), &mut writer)?;
+        let (_, got, _) = writer.finish();
+
+        if got != self.archive_hash {
+            return Err(eyre!(
+                "{:?} doesn't match its recorded hash, refusing to copy it",
+                self.store_path
+            )
+            .suggestion("Check it with miq store verify --repair"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Contents of the eval file of a store path, if it was built from a unit
+pub fn read_eval(path: &str) -> Result<Option<String>> {
+    let Some(result) = MiqResult::from_store_path(path) else {
+        return Ok(None);
+    };
+
+    match std::fs::read_to_string(result.eval_path().as_path()) {
+        Ok(text) => Ok(Some(text)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Parse the eval file of `path`, checking that the unit is the one that produces it
+pub fn parse_eval(path: &str, eval: &str) -> Result<Unit> {
+    let unit: Unit = toml::from_str(eval).wrap_err(format!("Parsing the unit of {:?}", path))?;
+    ensure!(
+        unit.result().store_path().as_path() == Path::new(path),
+        "The unit of {:?} is for {:?}",
+        path,
+        unit.result()
+    );
+    Ok(unit)
+}
+
+/// Hash of a unit, taken over its canonical JSON so that the formatting of the eval file doesn't
+/// change it
+pub fn unit_integrity(unit: &Unit) -> Result<Integrity> {
+    let mut hasher = crate::hash::IntegrityHasher::default();
+    hasher.update(crate::hash::canonical_json(unit)?);
+    Ok(hasher.finish())
+}
+
+/// Parse the unit that came with `metadata`, which is only trusted if its hash is in the metadata
+pub fn trusted_eval(metadata: &PathMetadata, eval: &str) -> Result<Unit> {
+    let path = &metadata.store_path;
+    let unit = parse_eval(path, eval)?;
+    let got = unit_integrity(&unit)?;
+
+    match metadata.eval_hash {
+        Some(expected) if expected == got => Ok(unit),
+        Some(expected) => bail!(
+            "Hash mismatch for the unit of {:?}: expected {}, got {}",
+            path,
+            expected,
+            got
+        ),
+        None => bail!("The unit of {:?} is not covered by its metadata", path),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedPath {
+    #[serde(flatten)]
+    path: PathMetadata,
+    /// Contents of the eval file, if the path was built from a unit
+    eval: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ExportArgs {
+    #[arg(value_hint = clap::ValueHint::DirPath, required = true)]
+    /// Store paths to export, along with their closure
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ImportArgs {}
+
+impl crate::Main for ExportArgs {
+    fn main(&self) -> Result<()> {
+        let stdout = std::io::stdout();
+        if stdout.is_terminal() {
+            return Err(eyre!("Refusing to write an export to a terminal")
+                .suggestion("Redirect the output to a file, like > closure.miq"));
+        }
+
+        let _lock = crate::lock::StoreLock::shared()?;
+        let conn = DbConnection::new()?;
+        let paths: Vec<PathBuf> = self
+            .paths
+            .iter()
+            .map(crate::db::normalize_store_path)
+            .collect();
+        let _roots = crate::gc::TempRoots::new(&paths)?;
+
+        let writer = BufWriter::new(stdout.lock());
+        let count = export(&conn, &paths, writer)?;
+        info!(?count, "Exported paths");
+
+        Ok(())
+    }
+}
+
+impl crate::Main for ImportArgs {
+    fn main(&self) -> Result<()> {
+        let _lock = crate::lock::StoreLock::shared()?;
+        let mut conn = DbConnection::new()?;
+        let reader = BufReader::new(std::io::stdin().lock());
+        let report = import(&mut conn, reader)?;
+
+        println!(
+            "{} paths imported, {} already present",
+            report.imported, report.present
+        );
+
+        Ok(())
+    }
+}
+
+/// Write the closure of `paths` into `w`, returning the number of paths written
+pub fn export<P: AsRef<Path>, W: Write>(conn: &DbConnection, paths: &[P], w: W) -> Result<usize> {
+    for path in paths {
+        ensure!(
+            conn.path_info(path)?.is_some(),
+            "{:?} is not a registered path",
+            path.as_ref()
+        );
+    }
+
+    let closure = conn.closure(paths)?;
+    // Referrers come first, so reverse it to write references first
+    let mut order = conn.deletion_order(closure)?;
+    order.reverse();
+
+    let mut encoder = zstd::Encoder::new(w, 0)?;
+    crate::archive::write_str(&mut encoder, MAGIC)?;
+
+    for path in &order {
+        let metadata = ExportedPath {
+            path: PathMetadata::read_signed(conn, path)?,
+            eval: read_eval(path)?,
+        };
+        debug!(?path, "Exporting");
+
+        crate::archive::write_str(&mut encoder, "path")?;
+        crate::archive::write_bytes(&mut encoder, &serde_json::to_vec(&metadata)?)?;
+        metadata.path.dump(&mut encoder)?;
+    }
+
+    crate::archive::write_str(&mut encoder, "end")?;
+    encoder.finish()?.flush()?;
+
+    Ok(order.len())
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub present: usize,
+}
+
+/// A path unpacked next to the store and verified, waiting to be moved into it
+pub struct Unpacked {
+    pub metadata: PathMetadata,
+    dir: TempDir,
+}
+
+impl Unpacked {
+    /// Unpack the archive read from `r`, checking it against the hash in `metadata`
+    pub fn new<R: Read>(r: R, metadata: PathMetadata) -> Result<Self> {
+        let path = metadata.store_path.clone();
+        ensure!(
+            MiqResult::from_store_path(&path).is_some(),
+            "{:?} is not a store path",
+            path
+        );
+
+        let dir = crate::build::store_tempdir("unpack")?;
+        let result = Self { metadata, dir };
+
+        crate::archive::restore(r, result.unpacked()).wrap_err(format!("Unpacking {:?}", path))?;
+
+        let (got, size) = crate::archive::hash_path(result.unpacked())?;
+        ensure!(
+            got == result.metadata.archive_hash && size == result.metadata.archive_size,
+            "Hash mismatch for {:?}: expected {}, got {}",
+            path,
+            result.metadata.archive_hash,
+            got
+        );
+
+        Ok(result)
+    }
+
+    fn unpacked(&self) -> PathBuf {
+        self.dir.path().join("out")
+    }
+}
+
+/// Move unpacked paths into the store, and register them in one transaction
+pub fn install(conn: &mut DbConnection, paths: &[Unpacked]) -> Result<()> {
+    let new_paths: Vec<&PathMetadata> = paths.iter().map(|entry| &entry.metadata).collect();
+
+    conn.add_many(&new_paths, || {
+        for entry in paths {
+            let path = crate::layout::get().physical(&entry.metadata.store_path);
+            crate::build::clean_path(&path)?;
+            std::fs::rename(entry.unpacked(), &path).wrap_err(format!("Moving {:?}", path))?;
+        }
+        Ok(())
+    })
+}
+
+fn read_token<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    crate::archive::read_bytes(r, 64)
+}
+
+/// Read an export from `r`, verifying and registering every path that is not in the store yet
+pub fn import<R: Read>(conn: &mut DbConnection, r: R) -> Result<ImportReport> {
+    let mut decoder = zstd::Decoder::new(r)?;
+    ensure!(
+        read_token(&mut decoder)? == MAGIC.as_bytes(),
+        "Not a miq export"
+    );
+
+    let mut report = ImportReport::default();
+    let mut pending: Vec<Unpacked> = Vec::new();
+    let mut units: Vec<Unit> = Vec::new();
+    let mut seen: BTreeSet<String> = BTreeSet::new();
+    let mut locks = Vec::new();
+    let mut roots = Vec::new();
+
+    loop {
+        match read_token(&mut decoder)?.as_slice() {
+            b"end" => break,
+            b"path" => {}
+            other => bail!("Unexpected {:?} in export", String::from_utf8_lossy(other)),
+        }
+
+        let metadata = crate::archive::read_bytes(&mut decoder, MAX_METADATA_LEN)?;
+        let ExportedPath {
+            path: metadata,
+            eval,
+        } = serde_json::from_slice(&metadata)?;
+        let path = metadata.store_path.clone();
+        debug!(?path, "Importing");
+        // Its references in the store must outlive the import
+        roots.push(TempRoots::new(metadata.references.iter().chain([&path]))?);
+
+        for reference in &metadata.references {
+            ensure!(
+                *reference == path || seen.contains(reference) || conn.is_db_path(reference)?,
+                "{:?} references {:?}, which is not in the export or in the store",
+                path,
+                reference
+            );
+        }
+
+        let mut new = false;
+        if seen.insert(path.clone()) {
+            // Another process might be adding it, so check once its lock is held
+            if let Some(result) = MiqResult::from_store_path(&path) {
+                locks.push(PathLock::lock(&result)?);
+            }
+            new = !conn.is_db_path(&path)?;
+        }
+        if new {
+            crate::signing::check_trusted(&metadata)?;
+            // Rebuilding runs the unit, so only write the ones that the signature covers
+            if let Some(eval) = eval {
+                units.push(trusted_eval(&metadata, &eval)?);
+            }
+        }
+
+        // Unpack even if the path is present, to read past its archive
+        let entry = Unpacked::new(&mut decoder, metadata)?;
+
+        if new {
+            pending.push(entry);
+        } else {
+            debug!(?path, "Already in the store");
+            report.present += 1;
+        }
+    }
+
+    for unit in units {
+        if !unit.result().eval_path().exists() {
+            unit.write_to_disk()?;
+        }
+    }
+
+    install(conn, &pending)?;
+
+    report.imported = pending.len();
+    Ok(report)
+}
+
+#[test]
+fn test_trusted_eval() {
+    use crate::schema_eval::Fetch;
+
+    let unit = Unit::FetchUnit(Fetch {
+        result: MiqResult::create("foo.tar.gz", &"foo"),
+        name: String::from("foo.tar.gz"),
+        url: String::from("https://example.com/foo.tar.gz"),
+        ..Default::default()
+    });
+    let eval = toml::to_string_pretty(&unit).unwrap();
+    let mut metadata = PathMetadata {
+        store_path: unit.result().store_path().to_string_lossy().into_owned(),
+        archive_hash: crate::hash::IntegrityHasher::default().finish(),
+        archive_size: 0,
+        references: Vec::new(),
+        signatures: Vec::new(),
+        eval_hash: Some(unit_integrity(&unit).unwrap()),
+    };
+    assert_eq!(trusted_eval(&metadata, &eval).unwrap(), unit);
+
+    // The header of the eval file is not part of the unit
+    let with_header = format!("#:schema /elsewhere/eval-schema.json\n{}", eval);
+    assert_eq!(trusted_eval(&metadata, &with_header).unwrap(), unit);
+
+    let tampered = eval.replace("https://example.com", "https://attacker.example.com");
+    assert!(trusted_eval(&metadata, &tampered)
+        .unwrap_err()
+        .to_string()
+        .contains("Hash mismatch"));
+
+    metadata.eval_hash = None;
+    assert!(trusted_eval(&metadata, &eval).is_err());
+
+    metadata.store_path = MiqResult::create("bar", &"bar")
+        .store_path()
+        .to_string_lossy()
+        .into_owned();
+    assert!(trusted_eval(&metadata, &eval).is_err());
+}
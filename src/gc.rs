@@ -0,0 +1,427 @@
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{bail, ensure, Context};
+use color_eyre::Result;
+use indicatif::HumanBytes;
+use nix::errno::Errno;
+use nix::fcntl::AtFlags;
+use nix::sys::stat::{fstatat, SFlag};
+use nix::unistd::{
+    fchownat, geteuid, symlinkat, unlinkat, FchownatFlags, Uid, UnlinkatFlags, User,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, trace, warn};
+
+use crate::db::DbConnection;
+use crate::hash::HASH_STORE_DIR;
+
+#[derive(Debug, Clone, clap::Args, Serialize, Deserialize)]
+/// Delete the store paths that are not reachable from a GC root
+pub struct Args {
+    /// Only print the paths that would be deleted
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Stop after freeing this many bytes. Accepts K, M, G and T suffixes
+    #[arg(long, value_parser = parse_size)]
+    max_freed: Option<u64>,
+}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        if let Some(client) = crate::daemon::Client::connect()? {
+            client.request(crate::daemon::Request::Gc(self.clone()))?;
+            return Ok(());
+        }
+
+        self.run(&mut crate::daemon::print_line)
+    }
+}
+
+impl Args {
+    pub fn run(&self, output: crate::daemon::Output) -> Result<()> {
+        let _lock = crate::lock::StoreLock::exclusive()?;
+        let mut conn = DbConnection::new()?;
+
+        let report = collect_garbage(&mut conn, self.dry_run, self.max_freed)?;
+
+        let verb = if self.dry_run { "would be" } else { "were" };
+        output(format!(
+            "{} paths {} deleted, {} freed",
+            report.deleted.len(),
+            verb,
+            HumanBytes(report.freed)
+        ))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub deleted: Vec<String>,
+    pub freed: u64,
+}
+
+/// Parse a size in bytes, with an optional K, M, G or T binary suffix
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last() {
+        Some('K' | 'k') => (&s[..s.len() - 1], 1 << 10),
+        Some('M' | 'm') => (&s[..s.len() - 1], 1 << 20),
+        Some('G' | 'g') => (&s[..s.len() - 1], 1 << 30),
+        Some('T' | 't') => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid size {:?}: {}", s, e))?;
+
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size {:?} is too big", s))
+}
+
+/// Register `link` as a root, so that whatever store path it points to is kept alive
+pub fn add_indirect_root<P: AsRef<Path> + Debug>(link: P) -> Result<()> {
+    let link = std::path::absolute(link.as_ref())?;
+    let link_str = link.to_str().unwrap();
+    let name = crate::hash::to_base32(&crate::hash::sha256(link_str)[..20]);
+    let auto_dir = crate::layout::get().gcroots_auto_dir();
+    let root = auto_dir.join(name);
+
+    std::fs::create_dir_all(&auto_dir)?;
+    // Not clean_path, which follows the root and skips it if the link is dangling
+    if root.symlink_metadata().is_ok() {
+        std::fs::remove_file(&root)?;
+    }
+    std::os::unix::fs::symlink(&link, &root)
+        .wrap_err(format!("Creating GC root {:?} -> {:?}", root, link))?;
+
+    debug!(?root, ?link, "Added indirect root");
+    Ok(())
+}
+
+/// Point `link` to `target`, and register it as a root
+pub fn create_out_link<P: AsRef<Path> + Debug, T: AsRef<Path> + Debug>(
+    link: P,
+    target: T,
+) -> Result<()> {
+    replace_link(&link, target)?;
+    add_indirect_root(link)
+}
+
+/// Like [create_out_link], for a user that asked the daemon to build. The link is only created in
+/// a directory that `owner` owns, through a descriptor of it so that it can't be swapped
+/// meanwhile, and it belongs to `owner`.
+pub fn create_out_link_as(link: &Path, target: &Path, owner: Uid) -> Result<()> {
+    ensure!(
+        link.is_absolute(),
+        "The out link {:?} is not absolute",
+        link
+    );
+    let (Some(parent), Some(name)) = (link.parent(), link.file_name()) else {
+        bail!("{:?} can't be an out link", link);
+    };
+
+    let dir = std::fs::File::open(parent).wrap_err(format!("Opening {:?}", parent))?;
+    let meta = dir.metadata()?;
+    ensure!(meta.is_dir(), "{:?} is not a directory", parent);
+    ensure!(
+        owner.is_root() || meta.uid() == owner.as_raw(),
+        "{:?} is not owned by the user that sent the request",
+        parent
+    );
+    let dirfd = Some(dir.as_raw_fd());
+
+    match fstatat(dir.as_raw_fd(), name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+        Ok(stat) if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFLNK => {
+            unlinkat(dirfd, name, UnlinkatFlags::NoRemoveDir)?
+        }
+        Ok(_) => bail!("Refusing to replace {:?}, which is not a symlink", link),
+        Err(Errno::ENOENT) => {}
+        Err(err) => bail!(err),
+    }
+
+    symlinkat(target, dirfd, name).wrap_err(format!("Creating out link {:?}", link))?;
+    if owner != geteuid() {
+        let group = User::from_uid(owner)?.map(|user| user.gid);
+        fchownat(
+            dirfd,
+            name,
+            Some(owner),
+            group,
+            FchownatFlags::NoFollowSymlink,
+        )?;
+    }
+
+    add_indirect_root(link)
+}
+
+#[test]
+fn test_create_out_link_as() {
+    let _store = crate::layout::test_store();
+    let dir = tempfile::tempdir().unwrap();
+    let link = dir.path().join("result");
+    let target = crate::layout::get().store_dir().join("foo-aaaa");
+
+    create_out_link_as(&link, &target, geteuid()).unwrap();
+    assert_eq!(std::fs::read_link(&link).unwrap(), target);
+    // Replacing it
+    create_out_link_as(&link, &target, geteuid()).unwrap();
+
+    std::fs::write(dir.path().join("file"), "").unwrap();
+    let err = create_out_link_as(&dir.path().join("file"), &target, geteuid()).unwrap_err();
+    assert!(err.to_string().contains("not a symlink"), "{}", err);
+
+    let other = Uid::from_raw(geteuid().as_raw() + 1);
+    let err = create_out_link_as(&dir.path().join("other"), &target, other).unwrap_err();
+    assert!(err.to_string().contains("not owned"), "{}", err);
+    assert!(!dir.path().join("other").exists());
+
+    assert!(create_out_link_as(Path::new("result"), &target, geteuid()).is_err());
+}
+
+/// Point `link` to `target`, replacing it if it's already a symlink
+pub fn replace_link<P: AsRef<Path> + Debug, T: AsRef<Path> + Debug>(
+    link: P,
+    target: T,
+) -> Result<()> {
+    let link = link.as_ref();
+
+    match std::fs::symlink_metadata(link) {
+        Ok(meta) if meta.is_symlink() => std::fs::remove_file(link)?,
+        Ok(_) => bail!("Refusing to replace {:?}, which is not a symlink", link),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => bail!(err),
+    }
+
+    std::os::unix::fs::symlink(target.as_ref(), link)
+        .wrap_err(format!("Creating out link {:?}", link))?;
+
+    Ok(())
+}
+
+/// If `path` is inside the store, return the top-level store path that contains it
+fn to_store_path(path: &Path) -> Option<PathBuf> {
+    let path = crate::layout::get().logical(path);
+    let rest = path.strip_prefix(HASH_STORE_DIR).ok()?;
+    let name = rest.components().next()?;
+    Some(Path::new(HASH_STORE_DIR).join(name))
+}
+
+/// Store paths pointed to by the symlinks under `dir`. Indirect roots whose link has gone away,
+/// or doesn't point to the store anymore, are removed unless `dry_run` is set.
+fn find_roots_in(dir: &Path, dry_run: bool, roots: &mut BTreeSet<PathBuf>) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => bail!(err),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        let meta = std::fs::symlink_metadata(&path)?;
+
+        if meta.is_dir() {
+            find_roots_in(&path, dry_run, roots)?;
+            continue;
+        }
+
+        if !meta.is_symlink() {
+            warn!(?path, "Ignoring GC root that is not a symlink");
+            continue;
+        }
+
+        let target = std::fs::read_link(&path)?;
+        if let Some(store_path) = to_store_path(&target) {
+            trace!(?path, ?store_path, "Direct root");
+            roots.insert(store_path);
+            continue;
+        }
+
+        // Indirect root: a link to a symlink somewhere else, like ./result
+        match std::fs::read_link(&target)
+            .ok()
+            .and_then(|t| to_store_path(&t))
+        {
+            Some(store_path) => {
+                trace!(?path, ?target, ?store_path, "Indirect root");
+                roots.insert(store_path);
+            }
+            None => {
+                info!(?path, ?target, "Removing stale root");
+                if !dry_run {
+                    std::fs::remove_file(&path)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn find_roots(dry_run: bool) -> Result<BTreeSet<PathBuf>> {
+    let mut roots = BTreeSet::new();
+    find_roots_in(&crate::layout::get().gcroots_dir(), dry_run, &mut roots)?;
+    roots.extend(crate::profile::roots()?);
+    Ok(roots)
+}
+
+/// Apparent size of a file or directory tree, not following symlinks
+pub fn path_size<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let meta = match std::fs::symlink_metadata(path.as_ref()) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => bail!(err),
+    };
+
+    let mut size = meta.len();
+    if meta.is_dir() {
+        for entry in std::fs::read_dir(path.as_ref())? {
+            size += path_size(entry?.path())?;
+        }
+    }
+
+    Ok(size)
+}
+
+/// Delete every registered path that is not in the closure of a root. Paths are deleted before
+/// the paths they reference, so stopping at `max_freed` never leaves a dangling reference.
+pub fn collect_garbage(
+    conn: &mut DbConnection,
+    dry_run: bool,
+    max_freed: Option<u64>,
+) -> Result<GcReport> {
+    let mut report = delete_dead_paths(conn, dry_run, max_freed)?;
+
+    if !dry_run {
+        report.freed += crate::optimise::remove_unused_links()?;
+    }
+
+    Ok(report)
+}
+
+/// Like [collect_garbage], while other processes may be using the store, so the paths they need
+/// must be kept alive by [TempRoots]. The links of `miq store optimise` are kept, as a process
+/// could be about to link to them.
+pub fn collect_garbage_shared(conn: &mut DbConnection, max_freed: Option<u64>) -> Result<GcReport> {
+    let _lock = crate::lock::RootsLock::exclusive()?;
+    delete_dead_paths(conn, false, max_freed)
+}
+
+fn delete_dead_paths(
+    conn: &mut DbConnection,
+    dry_run: bool,
+    max_freed: Option<u64>,
+) -> Result<GcReport> {
+    let roots = find_roots(dry_run)?;
+    debug!(?roots);
+
+    let live = conn.closure(&roots)?;
+    let dead: BTreeSet<String> = conn
+        .list()?
+        .into_iter()
+        .map(|p| p.store_path)
+        .filter(|p| !live.contains(p))
+        .collect();
+
+    let mut report = GcReport::default();
+
+    for path in conn.deletion_order(dead)? {
+        if max_freed.is_some_and(|max| report.freed >= max) {
+            break;
+        }
+
+        let size = path_size(crate::layout::get().physical(&path))?;
+        info!(?path, size = %HumanBytes(size), "Deleting");
+        if !dry_run {
+            conn.remove(&path)?;
+        }
+
+        report.deleted.push(path);
+        report.freed += size;
+    }
+
+    Ok(report)
+}
+
+/// Roots for the paths that a process holding the shared store lock produces or reads, until it's
+/// dropped, so that garbage collected meanwhile by [collect_garbage_shared] doesn't delete them.
+/// They must be added before the paths are, as the paths can't be told apart from garbage
+/// otherwise. The roots of processes that died are removed when the store is cleaned.
+#[derive(Debug)]
+pub struct TempRoots {
+    _dir: tempfile::TempDir,
+}
+
+impl TempRoots {
+    pub fn new<I, P>(paths: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let _lock = crate::lock::RootsLock::shared()?;
+        let parent = crate::layout::get().gcroots_temp_dir();
+        std::fs::create_dir_all(&parent)?;
+        let dir = tempfile::Builder::new()
+            .prefix(&format!("{}-", std::process::id()))
+            .tempdir_in(&parent)?;
+
+        for path in paths {
+            let path = path.as_ref();
+            let link = dir.path().join(path.file_name().unwrap());
+            match std::os::unix::fs::symlink(path, &link) {
+                Err(err) if err.kind() != std::io::ErrorKind::AlreadyExists => {
+                    return Err(err).wrap_err(format!("Creating temporary root {:?}", link))
+                }
+                _ => {}
+            }
+        }
+
+        debug!(dir = ?dir.path(), "Added temporary roots");
+        Ok(Self { _dir: dir })
+    }
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("1234"), Ok(1234));
+    assert_eq!(parse_size("10K"), Ok(10 * 1024));
+    assert_eq!(parse_size("3G"), Ok(3 * 1024 * 1024 * 1024));
+    assert!(parse_size("G").is_err());
+    assert!(parse_size("-1M").is_err());
+}
+
+#[test]
+fn test_to_store_path() {
+    assert_eq!(
+        to_store_path(Path::new("/miq/store/foo-aaaa/bin/foo")),
+        Some(PathBuf::from("/miq/store/foo-aaaa"))
+    );
+    assert_eq!(to_store_path(Path::new("/miq/store")), None);
+    assert_eq!(to_store_path(Path::new("/usr/bin/foo")), None);
+}
+
+#[test]
+fn test_temp_roots() {
+    let _store = crate::layout::test_store();
+    let mut conn = DbConnection::new().unwrap();
+    let result = crate::eval::MiqResult::create("temp-root", &"temp-root");
+
+    // Rooted before the path is added, like a build does
+    let roots = TempRoots::new([result.store_path()]).unwrap();
+    let path = crate::db::add_test_path(&mut conn, &result, "x");
+    let report = collect_garbage_shared(&mut conn, None).unwrap();
+    assert!(!report.deleted.contains(&path));
+    assert!(conn.is_db_path(&path).unwrap());
+
+    drop(roots);
+    let report = collect_garbage_shared(&mut conn, None).unwrap();
+    assert!(report.deleted.contains(&path));
+    assert!(!conn.is_db_path(&path).unwrap());
+}
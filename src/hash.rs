@@ -0,0 +1,229 @@
+//! Hashing of units into store path names.
+//!
+//! The hash part of a [`MiqResult`](crate::eval::MiqResult) is computed as:
+//!
+//! ```text
+//! base32(truncate_160(sha256("miq-unit:v1:/miq/store:" + name + ":" + canonical_json(input))))
+//! ```
+//!
+//! - `canonical_json` is the input serialized through [`serde_json::Value`]: object keys are
+//!   sorted, there is no whitespace, and `None` fields are written as `null`.
+//! - `truncate_160` keeps the first 20 bytes of the digest.
+//! - `base32` uses the Nix alphabet (`0-9a-z` without `e`, `o`, `t`, `u`), most significant bit
+//!   first, which yields exactly 32 characters.
+//!
+//! Fetches with a known [`Integrity`] use it as their `input` instead of the URL, see
+//! [`fixed_output_result`](crate::lua_fetch::fixed_output_result).
+//!
+//! Changing any of these steps changes every store path, so bump the `v1` tag if you do.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use color_eyre::eyre::{bail, Context};
+use color_eyre::Report;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Logical prefix of the store, hashed into every path
+pub const HASH_STORE_DIR: &str = "/miq/store";
+
+const HASH_SCHEME: &str = "miq-unit:v1";
+
+const BASE32_ALPHABET: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Length of the hash part of a store path name
+pub const HASH_PART_LEN: usize = 32;
+
+pub fn is_base32(byte: u8) -> bool {
+    BASE32_ALPHABET.contains(&byte)
+}
+
+pub fn sha256<B: AsRef<[u8]>>(bytes: B) -> [u8; 32] {
+    Sha256::digest(bytes.as_ref()).into()
+}
+
+/// Encode bytes with the Nix base32 alphabet, most significant bit first
+pub fn to_base32(bytes: &[u8]) -> String {
+    let len = (bytes.len() * 8).div_ceil(5);
+    let mut result = String::with_capacity(len);
+
+    for n in 0..len {
+        let bit = n * 5;
+        let byte = bit / 8;
+        let shift = bit % 8;
+
+        let hi = u16::from(bytes[byte]) << 8;
+        let lo = bytes
+            .get(byte + 1)
+            .copied()
+            .map(u16::from)
+            .unwrap_or_default();
+        let index = ((hi | lo) >> (11 - shift)) & 0x1f;
+
+        result.push(BASE32_ALPHABET[index as usize] as char);
+    }
+
+    result
+}
+
+/// Serialize a value into its canonical JSON form
+pub fn canonical_json<S: Serialize>(value: &S) -> serde_json::Result<Vec<u8>> {
+    let value = serde_json::to_value(value)?;
+    serde_json::to_vec(&value)
+}
+
+/// Compute the hash part of a store path for `name` built from `input`
+pub fn unit_hash<S: Serialize>(name: &str, input: &S) -> serde_json::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(HASH_SCHEME);
+    hasher.update(":");
+    hasher.update(HASH_STORE_DIR);
+    hasher.update(":");
+    hasher.update(name);
+    hasher.update(":");
+    hasher.update(canonical_json(input)?);
+    let digest: [u8; 32] = hasher.finalize().into();
+
+    Ok(to_base32(&digest[..20]))
+}
+
+/// Subresource-Integrity style content hash, like `sha256-<base64 digest>`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Integrity {
+    digest: [u8; 32],
+}
+
+const SRI_SHA256: &str = "sha256";
+
+impl std::fmt::Debug for Integrity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Integrity({})", self)
+    }
+}
+
+impl Integrity {
+    pub fn digest(&self) -> &[u8; 32] {
+        &self.digest
+    }
+}
+
+impl Display for Integrity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", SRI_SHA256, BASE64.encode(self.digest))
+    }
+}
+
+impl FromStr for Integrity {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((algo, encoded)) = s.split_once('-') else {
+            bail!(
+                "Integrity {:?} is not of the form <algo>-<base64 digest>",
+                s
+            );
+        };
+
+        if algo != SRI_SHA256 {
+            bail!(
+                "Unsupported integrity algorithm {:?}, use {}",
+                algo,
+                SRI_SHA256
+            );
+        }
+
+        let decoded = BASE64
+            .decode(encoded)
+            .wrap_err(format!("Decoding the digest of {:?}", s))?;
+
+        let Ok(digest) = decoded.try_into() else {
+            bail!("Integrity {:?} doesn't hold a 32 byte digest", s);
+        };
+
+        Ok(Self { digest })
+    }
+}
+
+impl TryFrom<String> for Integrity {
+    type Error = Report;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Integrity> for String {
+    fn from(value: Integrity) -> Self {
+        value.to_string()
+    }
+}
+
+/// Incrementally compute the [`Integrity`] of a byte stream
+#[derive(Debug, Default)]
+pub struct IntegrityHasher(Sha256);
+
+impl IntegrityHasher {
+    pub fn update<B: AsRef<[u8]>>(&mut self, bytes: B) {
+        self.0.update(bytes);
+    }
+
+    pub fn finish(self) -> Integrity {
+        Integrity {
+            digest: self.0.finalize().into(),
+        }
+    }
+}
+
+#[test]
+fn test_base32() {
+    assert_eq!(to_base32(&[]), "");
+    assert_eq!(to_base32(&[0x00; 5]), "00000000");
+    assert_eq!(to_base32(&[0xff; 5]), "zzzzzzzz");
+    assert_eq!(to_base32(&[0x08, 0x42, 0x10, 0x84, 0x21]), "11111111");
+    assert_eq!(to_base32(b"miq"), "dmlp2");
+}
+
+#[test]
+fn test_canonical_json_sorts_keys() {
+    #[derive(Serialize)]
+    struct Input {
+        zeta: u32,
+        alpha: Option<String>,
+    }
+
+    let input = Input {
+        zeta: 1,
+        alpha: None,
+    };
+    let output = String::from_utf8(canonical_json(&input).unwrap()).unwrap();
+    assert_eq!(output, r#"{"alpha":null,"zeta":1}"#);
+}
+
+#[test]
+fn test_unit_hash_len() {
+    let hash = unit_hash("hello", &"world").unwrap();
+    assert_eq!(hash.len(), HASH_PART_LEN);
+    assert!(hash.bytes().all(is_base32));
+}
+
+#[test]
+fn test_integrity() {
+    let mut hasher = IntegrityHasher::default();
+    hasher.update("hello ");
+    hasher.update("world");
+    let integrity = hasher.finish();
+
+    let expected = "sha256-uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=";
+    assert_eq!(integrity.to_string(), expected);
+    assert_eq!(expected.parse::<Integrity>().unwrap(), integrity);
+
+    assert!("sha512-uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek="
+        .parse::<Integrity>()
+        .is_err());
+    assert!("sha256-aGVsbG8=".parse::<Integrity>().is_err());
+    assert!("FIXME".parse::<Integrity>().is_err());
+}
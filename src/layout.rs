@@ -0,0 +1,191 @@
+//! Where the store and the files around it live on disk.
+//!
+//! Everything lives under a root directory, `/miq` by default, which can be changed with
+//! `--store`, `$MIQ_STORE` or `store` in the config, for example to use a throwaway store in tests.
+//!
+//! Store paths keep their logical name under [HASH_STORE_DIR] everywhere: in hashes, in the
+//! database, in signatures, and in the outputs of builds, which see the root mounted at `/miq`.
+//! Only their files move, to [StoreLayout::physical].
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use once_cell::sync::OnceCell;
+use tracing::debug;
+
+use crate::hash::HASH_STORE_DIR;
+
+pub const DEFAULT_ROOT: &str = "/miq";
+
+/// Where eval files are, for the users that refer to them by their path under the default root
+pub const LOGICAL_EVAL_DIR: &str = "/miq/eval";
+
+static LAYOUT: OnceCell<StoreLayout> = OnceCell::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreLayout {
+    root: PathBuf,
+}
+
+impl StoreLayout {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where the files of the store paths are
+    pub fn store_dir(&self) -> PathBuf {
+        self.root.join("store")
+    }
+
+    pub fn eval_dir(&self) -> PathBuf {
+        self.root.join("eval")
+    }
+
+    pub fn log_dir(&self) -> PathBuf {
+        self.root.join("log")
+    }
+
+    pub fn locks_dir(&self) -> PathBuf {
+        self.root.join("locks")
+    }
+
+    pub fn gcroots_dir(&self) -> PathBuf {
+        self.root.join("gcroots")
+    }
+
+    /// Roots created by `miq build --out-link`, which point to the out-link instead of the store
+    pub fn gcroots_auto_dir(&self) -> PathBuf {
+        self.gcroots_dir().join("auto")
+    }
+
+    /// Roots of the paths that running builds need, see [crate::gc::TempRoots]
+    pub fn gcroots_temp_dir(&self) -> PathBuf {
+        self.gcroots_dir().join("temp")
+    }
+
+    /// Profiles of the users, see [crate::profile]
+    pub fn profiles_dir(&self) -> PathBuf {
+        self.root.join("profiles")
+    }
+
+    pub fn db_path(&self) -> PathBuf {
+        self.root.join("db.sqlite")
+    }
+
+    pub fn eval_schema(&self) -> PathBuf {
+        self.root.join("eval-schema.json")
+    }
+
+    pub fn daemon_socket(&self) -> PathBuf {
+        self.root.join("daemon.sock")
+    }
+
+    /// Where a store path or an eval file under [LOGICAL_EVAL_DIR], or a file inside of them, is
+    /// on disk. Other paths are returned as is.
+    pub fn physical<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let path = path.as_ref();
+        if let Ok(rest) = path.strip_prefix(LOGICAL_EVAL_DIR) {
+            return self.eval_dir().join(rest);
+        }
+        match path.strip_prefix(HASH_STORE_DIR) {
+            Ok(rest) if rest.as_os_str().is_empty() => self.store_dir(),
+            Ok(rest) => self.store_dir().join(rest),
+            Err(_) => path.to_owned(),
+        }
+    }
+
+    /// The store path of a file in the physical store. Other paths are returned as is.
+    pub fn logical<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let path = path.as_ref();
+        match path.strip_prefix(self.store_dir()) {
+            Ok(rest) if rest.as_os_str().is_empty() => PathBuf::from(HASH_STORE_DIR),
+            Ok(rest) => Path::new(HASH_STORE_DIR).join(rest),
+            Err(_) => path.to_owned(),
+        }
+    }
+}
+
+/// Use `root`, or the one from the config, as the root for the rest of the process
+pub fn init(root: Option<PathBuf>) -> Result<()> {
+    let root = root
+        .or_else(|| crate::config::get().store.clone())
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_ROOT));
+    let layout = StoreLayout::new(std::path::absolute(root)?);
+    debug!(?layout);
+
+    LAYOUT
+        .set(layout)
+        .map_err(|_| eyre!("The store layout was already set"))
+}
+
+/// The layout set by [init], or the default one
+pub fn get() -> &'static StoreLayout {
+    LAYOUT.get_or_init(default_layout)
+}
+
+#[cfg(not(test))]
+fn default_layout() -> StoreLayout {
+    StoreLayout::new(DEFAULT_ROOT)
+}
+
+/// Tests never touch the real store, they share a throwaway one for the whole test binary
+#[cfg(test)]
+fn default_layout() -> StoreLayout {
+    let root = tempfile::Builder::new()
+        .prefix("miq-test-")
+        .tempdir()
+        .unwrap()
+        .into_path();
+    let layout = StoreLayout::new(root);
+    for dir in [
+        layout.store_dir(),
+        layout.eval_dir(),
+        layout.log_dir(),
+        layout.gcroots_auto_dir(),
+    ] {
+        std::fs::create_dir_all(dir).unwrap();
+    }
+    layout
+}
+
+/// Take the throwaway store of the tests. Tests that use the database or collect garbage hold it,
+/// so that they don't see each other's paths.
+#[cfg(test)]
+pub fn test_store() -> std::sync::MutexGuard<'static, ()> {
+    static TEST_STORE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let guard = TEST_STORE.lock().unwrap_or_else(|err| err.into_inner());
+    get();
+    guard
+}
+
+#[test]
+fn test_layout_paths() {
+    let layout = StoreLayout::new("/tmp/test-store");
+    assert_eq!(layout.store_dir(), Path::new("/tmp/test-store/store"));
+    assert_eq!(
+        layout.physical("/miq/store/foo-aaaa/bin/foo"),
+        Path::new("/tmp/test-store/store/foo-aaaa/bin/foo")
+    );
+    assert_eq!(layout.physical("/miq/store"), layout.store_dir());
+    assert_eq!(layout.physical("/usr/bin/foo"), Path::new("/usr/bin/foo"));
+    assert_eq!(
+        layout.physical("/miq/eval/foo-aaaa.toml"),
+        Path::new("/tmp/test-store/eval/foo-aaaa.toml")
+    );
+    assert_eq!(
+        layout.logical("/tmp/test-store/store/foo-aaaa"),
+        Path::new("/miq/store/foo-aaaa")
+    );
+    assert_eq!(layout.logical("/usr/bin/foo"), Path::new("/usr/bin/foo"));
+
+    let default = StoreLayout::new(DEFAULT_ROOT);
+    assert_eq!(
+        default.physical("/miq/store/foo-aaaa"),
+        default.logical("/miq/store/foo-aaaa")
+    );
+}
@@ -0,0 +1,166 @@
+//! Locks between miq processes, taken with flock(2) on files in the locks directory of the
+//! [layout](crate::layout).
+//!
+//! - Commands that write to the store hold the [StoreLock] shared, and `miq gc` holds it
+//!   exclusively, so that nothing is collected while another process is using it.
+//! - A path is built, fetched, substituted or imported while holding its [PathLock], so that
+//!   concurrent builds wait for each other instead of producing the same path twice.
+//! - Garbage is also collected under the shared [StoreLock] when builds run low on disk space, so
+//!   the paths that other processes use are kept by temporary roots, added under the [RootsLock].
+//!
+//! Commands that only read from the store don't take any lock.
+
+use std::fs::File;
+use std::os::fd::AsRawFd;
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use indicatif::ProgressBar;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use tracing::{debug, info};
+
+use crate::eval::MiqResult;
+
+const STORE_LOCK: &str = "store";
+const ROOTS_LOCK: &str = "roots";
+
+fn open(name: &str) -> Result<File> {
+    let dir = crate::layout::get().locks_dir();
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(name);
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .wrap_err(format!("Opening lock {:?}", path))
+}
+
+/// Take the lock if it's free, returning false if another process holds it
+fn try_flock(file: &File, arg: FlockArg) -> Result<bool> {
+    match flock(file.as_raw_fd(), arg) {
+        Ok(()) => Ok(true),
+        Err(Errno::EWOULDBLOCK) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(Debug)]
+pub struct StoreLock {
+    _file: File,
+}
+
+impl StoreLock {
+    /// Take the lock to write to the store. When no other process holds it, the store is cleaned
+    /// of what interrupted processes left behind first.
+    pub fn shared() -> Result<Self> {
+        let file = open(STORE_LOCK)?;
+
+        if try_flock(&file, FlockArg::LockExclusiveNonblock)? {
+            clean()?;
+        } else {
+            debug!("The store is in use by another process, not cleaning it");
+        }
+
+        if !try_flock(&file, FlockArg::LockSharedNonblock)? {
+            info!("Waiting for the garbage collector to finish");
+            flock(file.as_raw_fd(), FlockArg::LockShared)?;
+        }
+
+        Ok(Self { _file: file })
+    }
+
+    /// Take the lock as the only process using the store, waiting for the others to finish
+    pub fn exclusive() -> Result<Self> {
+        let file = open(STORE_LOCK)?;
+
+        if !try_flock(&file, FlockArg::LockExclusiveNonblock)? {
+            info!("Waiting for other miq processes to finish");
+            flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+        }
+        clean()?;
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// Held shared while adding [TempRoots](crate::gc::TempRoots), and exclusively by garbage
+/// collection under the shared [StoreLock] from reading the roots until it's done deleting, so
+/// that it never deletes a path that was rooted in between.
+#[derive(Debug)]
+pub struct RootsLock {
+    _file: File,
+}
+
+impl RootsLock {
+    pub fn shared() -> Result<Self> {
+        let file = open(ROOTS_LOCK)?;
+
+        if !try_flock(&file, FlockArg::LockSharedNonblock)? {
+            info!("Waiting for the garbage collector to finish");
+            flock(file.as_raw_fd(), FlockArg::LockShared)?;
+        }
+
+        Ok(Self { _file: file })
+    }
+
+    pub fn exclusive() -> Result<Self> {
+        let file = open(ROOTS_LOCK)?;
+
+        if !try_flock(&file, FlockArg::LockExclusiveNonblock)? {
+            debug!("Waiting for temporary roots to be added");
+            flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+        }
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// Clean the store and the path locks, which nobody else can be using while the store lock is
+/// held exclusively
+fn clean() -> Result<()> {
+    crate::build::clean_store()?;
+    crate::build::clean_path(crate::layout::get().gcroots_temp_dir())?;
+
+    for entry in std::fs::read_dir(crate::layout::get().locks_dir())? {
+        let entry = entry?;
+        if entry.file_name() != STORE_LOCK {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct PathLock {
+    _file: File,
+}
+
+impl PathLock {
+    /// Lock the store path of `result`, waiting if another process holds it
+    pub fn lock(result: &MiqResult) -> Result<Self> {
+        let file = open(result.as_str())?;
+
+        if !try_flock(&file, FlockArg::LockExclusiveNonblock)? {
+            info!(?result, "Waiting for another process to produce the path");
+            flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+        }
+
+        Ok(Self { _file: file })
+    }
+
+    /// Like [PathLock::lock], showing in `pb` that it's waiting, without blocking the runtime
+    pub async fn lock_async(result: &MiqResult, pb: &ProgressBar) -> Result<Self> {
+        let file = open(result.as_str())?;
+        if try_flock(&file, FlockArg::LockExclusiveNonblock)? {
+            return Ok(Self { _file: file });
+        }
+
+        pb.set_message(format!("{} (waiting for another process)", result.name()));
+        let result = result.clone();
+        tokio::task::spawn_blocking(move || Self::lock(&result)).await?
+    }
+}
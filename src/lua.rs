@@ -1,11 +1,14 @@
 use std::hash::Hash;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Mutex;
 
 use color_eyre::eyre::{bail, Context, ContextCompat};
 use color_eyre::{Help, Report, Result};
 use mlua::prelude::*;
 use mlua::{chunk, StdLib, Table, Value};
+use nix::unistd::Uid;
 use serde::{Deserialize, Serialize};
 use tracing::{instrument, trace};
 
@@ -23,8 +26,8 @@ pub struct Args {
 
 impl crate::Main for Args {
     fn main(&self) -> Result<()> {
-        let lua = create_lua_env()?;
-        self.luaref.get_toplevel(&lua)?;
+        let lua = create_lua_env(None)?;
+        self.luaref.get_toplevel(&lua, None)?;
         Ok(())
     }
 }
@@ -61,8 +64,28 @@ impl FromStr for LuaRef {
     }
 }
 
+impl std::fmt::Display for LuaRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.root.to_string_lossy())?;
+        if let Some(element) = &self.element {
+            write!(f, "#{}", element.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+/// Evaluating changes the working directory of the process, so the daemon can't evaluate two
+/// files at once
+static EVAL_LOCK: Mutex<()> = Mutex::new(());
+
 impl LuaRef {
-    pub fn get_toplevel<'lua, 'result>(&self, lua: &'lua Lua) -> Result<Table<'result>>
+    /// Evaluate the root file, which is read for `reader` if it's evaluated for another user, see
+    /// [crate::daemon::open_as]
+    pub fn get_toplevel<'lua, 'result>(
+        &self,
+        lua: &'lua Lua,
+        reader: Option<Uid>,
+    ) -> Result<Table<'result>>
     where
         'lua: 'result,
     {
@@ -70,12 +93,18 @@ impl LuaRef {
         std::env::set_current_dir(parent_path)
             .wrap_err(format!("Changing directory to {:?}", parent_path))?;
 
-        let export: Table = lua
-            .load(self.root.as_path())
-            .eval()
-            .wrap_err("Loading root file")?;
+        let chunk = match reader {
+            None => lua.load(self.root.as_path()),
+            Some(uid) => {
+                let mut contents = String::new();
+                crate::daemon::open_as(&self.root, uid)?.read_to_string(&mut contents)?;
+                lua.load(contents)
+                    .set_name(format!("@{}", self.root.to_string_lossy()))
+            }
+        };
+        let export: Table = chunk.eval().wrap_err("Loading root file")?;
 
-        luatrace(&lua, export.clone())?;
+        luatrace(lua, export.clone())?;
 
         Ok(export)
     }
@@ -83,8 +112,22 @@ impl LuaRef {
 
 impl RefToUnit for LuaRef {
     fn ref_to_unit(&self) -> Result<Unit> {
-        let lua = create_lua_env()?;
-        let mut export: Table = self.get_toplevel(&lua)?;
+        self.eval(None)
+    }
+}
+
+impl LuaRef {
+    /// Like [RefToUnit::ref_to_unit], for a file that the user `reader` asked to evaluate. See
+    /// [sandbox] for what it can't do.
+    pub fn ref_to_unit_sandboxed(&self, reader: Uid) -> Result<Unit> {
+        self.eval(Some(reader))
+    }
+
+    fn eval(&self, reader: Option<Uid>) -> Result<Unit> {
+        let _lock = EVAL_LOCK.lock().unwrap();
+        let dir = self.root.parent().wrap_err("Reading the parent folder")?;
+        let lua = create_lua_env(reader.map(|uid| (dir, uid)))?;
+        let mut export: Table = self.get_toplevel(&lua, reader)?;
 
         let mut elements = match &self.element {
             None => bail!("Didn't specify which element to evaluate"),
@@ -139,14 +182,30 @@ where
 // static LUA_INSPECT: &str = std::include_str!("inspect.lua");
 // static LUA_F: &str = std::include_str!("f.lua");
 
-fn create_lua_env() -> Result<Lua> {
+/// `sandbox` is the directory of the root file and the user it's evaluated for, if it's evaluated
+/// for another user, see [sandbox]
+fn create_lua_env(sandbox: Option<(&Path, Uid)>) -> Result<Lua> {
+    // Debug is needed for f-string shenanigans
+    let libs = if sandbox.is_some() {
+        StdLib::COROUTINE
+            | StdLib::TABLE
+            | StdLib::STRING
+            | StdLib::UTF8
+            | StdLib::MATH
+            | StdLib::PACKAGE
+            | StdLib::DEBUG
+    } else {
+        StdLib::ALL_SAFE | StdLib::DEBUG
+    };
     let lua = unsafe {
         Lua::unsafe_new_with(
-            // Needed for f-string shenanigans
-            StdLib::ALL_SAFE | StdLib::DEBUG,
+            libs,
             LuaOptions::new(), // .catch_rust_panics(false),
         )
     };
+    if let Some((dir, reader)) = sandbox {
+        self::sandbox(&lua, dir, reader)?;
+    }
 
     let module = get_or_create_module(&lua, "miq")?;
 
@@ -160,6 +219,7 @@ fn create_lua_env() -> Result<Lua> {
         })?,
     )?;
 
+    crate::lua_env::add_to_module(&lua, &module)?;
     crate::lua_fetch::add_to_module(&lua, &module)?;
     crate::lua_package::add_to_module(&lua, &module)?;
 
@@ -177,6 +237,79 @@ fn create_lua_env() -> Result<Lua> {
     Ok(lua)
 }
 
+/// Keep a file evaluated for another user, like by the daemon, from running anything as the owner
+/// of the store. There is no `io` or `os`, the debug library only keeps what f-strings use to read
+/// locals, and C modules and bytecode can't be loaded. Files can only be read as Lua modules with
+/// `require`, from `dir` whatever `package.path` is, and if `reader` can read them.
+fn sandbox(lua: &Lua, dir: &Path, reader: Uid) -> Result<()> {
+    let dir = dir.to_owned();
+    let searcher = lua.create_function(move |lua, name: String| {
+        let module = name.replace('.', "/");
+        let relative = Path::new(&module);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            let message = format!("\n\tinvalid module name {:?}", name);
+            return Ok((message.into_lua(lua)?, Value::Nil));
+        }
+
+        let mut message = String::new();
+        for path in [
+            dir.join(format!("{}.lua", module)),
+            dir.join(&module).join("init.lua"),
+        ] {
+            let mut contents = String::new();
+            let read = crate::daemon::open_as(&path, reader)
+                .and_then(|mut file| Ok(file.read_to_string(&mut contents)?));
+            if read.is_err() {
+                message.push_str(&format!("\n\tno readable file {:?}", path));
+                continue;
+            }
+
+            let name = path.to_string_lossy();
+            let loader = lua
+                .load(contents)
+                .set_name(format!("@{}", name))
+                .into_function()?;
+            return Ok((loader.into_lua(lua)?, name.into_lua(lua)?));
+        }
+        Ok((message.into_lua(lua)?, Value::Nil))
+    })?;
+
+    lua.load(
+        r##"
+        debug = { getinfo = debug.getinfo, getlocal = debug.getlocal }
+        package.loaded.debug = debug
+        package.loadlib = nil
+        package.searchpath = nil
+        package.path = ""
+        package.cpath = ""
+        package.searchers = { package.searchers[1], package.searchers[2] }
+        dofile = nil
+        loadfile = nil
+
+        local load = load
+        _G.load = function(chunk, name, _, ...)
+            -- An explicit nil environment is not the same as no environment
+            if select("#", ...) > 0 then
+                return load(chunk, name, "t", ...)
+            end
+            return load(chunk, name, "t")
+        end
+        "##,
+    )
+    .set_name("sandbox")
+    .exec()
+    .wrap_err("Sandboxing the Lua environment")?;
+
+    // In place of the searcher of Lua files
+    let package: Table = lua.globals().get("package")?;
+    let searchers: Table = package.get("searchers")?;
+    searchers.set(2, searcher)?;
+    Ok(())
+}
+
 static LUA_INSPECT: &str = std::include_str!("lua/inspect.lua");
 static LUA_F: &str = std::include_str!("lua/f.lua");
 
@@ -269,3 +402,87 @@ pub struct MetaText {
     pub deps: Vec<MiqResult>,
     pub value: String,
 }
+
+/// Results of the bootstrap recipes, which change whenever the hashing or the recipes do
+#[test]
+fn test_eval_bootstrap_golden() {
+    let _store = crate::layout::test_store();
+    let cases = [
+        (
+            "stage0.lua#bootstrap_tools",
+            "bootstrap-tools.tar.xz-1w6jvy3gzfg8b13y0nf3gck56dfkqa5d",
+        ),
+        (
+            "stage0.lua#unpack_bootstrap_tools",
+            "unpack-bootstrap-tools.sh-647jb6fgw70zibalf8qsvc2ilq65wmra",
+        ),
+        (
+            "stage0.lua#bootstrap",
+            "bootstrap-xwsbghb2kw8v6a8q6ycb637m8n2kj939",
+        ),
+        ("stage0.lua#test", "test-bbf0aqsn6aqms8qh4q3bn6gdsk5z3w29"),
+        ("stage1.lua#test", "test-laka85kx30rxp2m6h3p1cj72j52pn0qd"),
+    ];
+
+    for (luaref, expected) in cases {
+        let luaref = format!("{}/pkgs/{}", env!("CARGO_MANIFEST_DIR"), luaref);
+        let unit = LuaRef::from_str(&luaref).unwrap().ref_to_unit().unwrap();
+        assert_eq!(unit.result().as_str(), expected, "{}", luaref);
+    }
+}
+
+#[test]
+fn test_sandbox() {
+    let _store = crate::layout::test_store();
+    let root = crate::layout::get().root();
+    let file = root.join("sandbox.lua");
+    std::fs::write(root.join("sandbox_name.lua"), r#"return "sandbox""#).unwrap();
+    let other = tempfile::tempdir().unwrap();
+    std::fs::write(other.path().join("other.lua"), "return 1").unwrap();
+    // Neither changing package.path nor an absolute module name reach files out of the directory
+    let escape = format!(
+        r#"
+        package.path = "{}/?.lua"
+        assert(not pcall(require, "other") and not pcall(require, "{}"))
+        "#,
+        other.path().display(),
+        other.path().join("other").display(),
+    );
+    std::fs::write(
+        &file,
+        escape
+            + r#"
+        local miq = require "miq"
+        local f = miq.f
+        assert(os == nil and io == nil and dofile == nil and loadfile == nil)
+        assert(debug.sethook == nil and debug.getregistry == nil and debug.setupvalue == nil)
+        assert(require("debug") == debug)
+        assert(package.loadlib == nil and #package.searchers == 2)
+        assert(load(string.dump(function() end)) == nil)
+        assert(load("return 1")() == 1 and load("return x", "x", "t", { x = 2 })() == 2)
+
+        local name = require "sandbox_name"
+        return { unit = miq.fetch { url = f "https://example.com/{{name}}" } }
+        "#,
+    )
+    .unwrap();
+
+    let luaref = LuaRef::from_str(&format!("{}#unit", file.display())).unwrap();
+    let unit = luaref
+        .ref_to_unit_sandboxed(nix::unistd::geteuid())
+        .unwrap();
+    assert_eq!(unit.result().name(), "sandbox");
+    // Outside of the sandbox, os is there
+    let err = format!("{:#}", luaref.ref_to_unit().unwrap_err());
+    assert!(err.contains("assertion failed"), "{}", err);
+
+    let luaref = format!("{}/pkgs/stage0.lua#bootstrap", env!("CARGO_MANIFEST_DIR"));
+    let unit = LuaRef::from_str(&luaref)
+        .unwrap()
+        .ref_to_unit_sandboxed(nix::unistd::geteuid())
+        .unwrap();
+    assert_eq!(
+        unit.result().as_str(),
+        "bootstrap-xwsbghb2kw8v6a8q6ycb637m8n2kj939"
+    );
+}
@@ -0,0 +1,56 @@
+use mlua::prelude::*;
+use mlua::{Lua, Table, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::eval::MiqResult;
+use crate::schema_eval::{Env, Unit};
+
+/// Input to the lua env function, which will transform it into a proper Env
+#[derive(Debug, Serialize, Deserialize)]
+struct EnvInput {
+    name: String,
+    paths: Vec<Unit>,
+    ignore_collisions: Option<bool>,
+}
+
+fn env<'lua, 'result>(ctx: &'lua Lua, input: Value<'result>) -> Result<Value<'result>, LuaError>
+where
+    'lua: 'result,
+{
+    let user_input: EnvInput = ctx.from_value(input)?;
+    let result_unit = Unit::try_from(user_input)?;
+    let repr = ctx.to_value(&result_unit)?;
+    Ok(repr)
+}
+
+pub fn add_to_module(ctx: &Lua, module: &Table) -> Result<(), LuaError> {
+    module.set("env", ctx.create_function(env)?)?;
+    Ok(())
+}
+
+impl TryFrom<EnvInput> for Unit {
+    type Error = LuaError;
+
+    fn try_from(value: EnvInput) -> std::result::Result<Self, Self::Error> {
+        let result = MiqResult::create(&value.name, &value);
+
+        // Keep the order, as it decides which path wins a collision
+        let mut paths: Vec<MiqResult> = Vec::new();
+        for unit in &value.paths {
+            if !paths.contains(unit.result()) {
+                paths.push(unit.result().clone());
+            }
+        }
+
+        let inner = Env {
+            result,
+            name: value.name,
+            paths,
+            ignore_collisions: value.ignore_collisions.unwrap_or_default(),
+        };
+
+        let unit = Unit::EnvUnit(inner);
+        unit.write_to_disk().expect("Failed to write to disk");
+        Ok(unit)
+    }
+}
@@ -4,15 +4,66 @@ use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::eval::MiqResult;
+use crate::hash::Integrity;
 use crate::schema_eval::{Fetch, Unit};
 
 /// Input to the lua fetch function, which will transform it into a proper Fetch
-#[derive(Educe, Serialize, Deserialize, Hash)]
+#[derive(Educe, Serialize, Deserialize)]
 #[educe(Debug)]
 pub struct FetchInput {
     #[educe(Debug(trait = "std::fmt::Display"))]
     pub url: Url,
     pub executable: Option<bool>,
+    /// SRI hash of the file, like `sha256-<base64>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Integrity>,
+    /// Name of the store path, defaults to the last segment of the URL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// What a fetch with a known hash is addressed by. The URL is left out, so that mirrors of the
+/// same file share a store path. The executable bit is kept, as it changes the output.
+#[derive(Debug, Serialize)]
+struct FixedOutput<'a> {
+    integrity: &'a Integrity,
+    executable: bool,
+}
+
+/// Result of a fetch whose contents are known in advance
+pub fn fixed_output_result(name: &str, integrity: &Integrity, executable: bool) -> MiqResult {
+    MiqResult::create(
+        name,
+        &FixedOutput {
+            integrity,
+            executable,
+        },
+    )
+}
+
+impl FetchInput {
+    fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .url
+                .path_segments()
+                .expect("URL doesn't have segments")
+                .next_back()
+                .unwrap()
+                .to_owned(),
+        }
+    }
+
+    fn result(&self) -> MiqResult {
+        let name = self.name();
+        match &self.hash {
+            Some(integrity) => {
+                fixed_output_result(&name, integrity, self.executable.unwrap_or_default())
+            }
+            None => MiqResult::create(&name, self),
+        }
+    }
 }
 
 impl TryFrom<FetchInput> for Unit {
@@ -20,22 +71,16 @@ impl TryFrom<FetchInput> for Unit {
 
     #[tracing::instrument(level = "trace", ret, err)]
     fn try_from(value: FetchInput) -> std::result::Result<Self, Self::Error> {
-        let name = value
-            .url
-            .path_segments()
-            .expect("URL doesn't have segments")
-            .last()
-            .unwrap()
-            .to_owned();
-
-        let result = MiqResult::create(&name, &value);
+        let name = value.name();
+        let executable = value.executable.unwrap_or_default();
+        let result = value.result();
 
         let inner = Fetch {
             result,
             name,
             url: value.url.to_string(),
-            integrity: String::from("FIXME"),
-            executable: value.executable.unwrap_or_default(),
+            integrity: value.hash,
+            executable,
         };
 
         let unit = Unit::FetchUnit(inner);
@@ -59,3 +104,62 @@ pub fn add_to_module(ctx: &Lua, module: &Table) -> Result<(), LuaError> {
     module.set("fetch", ctx.create_function(fetch)?)?;
     Ok(())
 }
+
+#[test]
+fn test_fetch_result_golden() {
+    // Mirrors pkgs/stage0.lua
+    let bootstrap_tools = FetchInput {
+        url: Url::parse("https://wdtz.org/files/gywxhjgl70sxippa0pxs0vj5qcgz1wi8-stdenv-bootstrap-tools/on-server/bootstrap-tools.tar.xz").unwrap(),
+        executable: None,
+        hash: None,
+        name: None,
+    };
+    let result = bootstrap_tools.result();
+    assert_eq!(
+        result.as_str(),
+        "bootstrap-tools.tar.xz-1w6jvy3gzfg8b13y0nf3gck56dfkqa5d"
+    );
+
+    let unpack_bootstrap_tools = FetchInput {
+        url: Url::parse("https://raw.githubusercontent.com/NixOS/nixpkgs/d6b863fd9b7bb962e6f9fdf292419a775e772891/pkgs/stdenv/linux/bootstrap-tools-musl/scripts/unpack-bootstrap-tools.sh").unwrap(),
+        executable: Some(true),
+        hash: None,
+        name: None,
+    };
+    let result = unpack_bootstrap_tools.result();
+    assert_eq!(
+        result.as_str(),
+        "unpack-bootstrap-tools.sh-647jb6fgw70zibalf8qsvc2ilq65wmra"
+    );
+}
+
+#[test]
+fn test_fetch_fixed_output() {
+    let integrity: Integrity = "sha256-uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek="
+        .parse()
+        .unwrap();
+
+    let input = FetchInput {
+        url: Url::parse("https://example.org/hello.txt").unwrap(),
+        executable: None,
+        hash: Some(integrity),
+        name: None,
+    };
+    let result = input.result();
+    assert_eq!(
+        result.as_str(),
+        "hello.txt-f9wz0ay55bw337p292kdn9jwi0ciya3y"
+    );
+
+    let mirror = FetchInput {
+        url: Url::parse("https://mirror.example.com/pub/hello.txt").unwrap(),
+        ..input
+    };
+    assert_eq!(mirror.result(), result);
+
+    let executable = FetchInput {
+        executable: Some(true),
+        ..mirror
+    };
+    assert_ne!(executable.result(), result);
+}
@@ -11,7 +11,7 @@ use crate::lua::MetaTextInput;
 use crate::schema_eval::{Package, Unit};
 
 /// Input to the lua package function, which will transform it into a proper Package
-#[derive(Debug, Serialize, Deserialize, Hash)]
+#[derive(Debug, Serialize, Deserialize)]
 struct PackageInput {
     name: String,
     version: Option<String>,
@@ -51,10 +51,7 @@ impl TryFrom<PackageInput> for Unit {
             .deps
             .unwrap_or_default()
             .iter()
-            .map(|elem| match elem {
-                Unit::PackageUnit(inner) => inner.result.clone(),
-                Unit::FetchUnit(inner) => inner.result.clone(),
-            })
+            .map(|elem| elem.result().clone())
             .collect::<BTreeSet<_>>();
 
         trace!(?deps);
@@ -100,3 +97,27 @@ impl TryFrom<PackageInput> for Unit {
         Ok(unit)
     }
 }
+
+#[test]
+fn test_package_result_golden() {
+    // Mirrors pkgs/init.lua
+    let empty1 = PackageInput {
+        name: "empty1".into(),
+        version: None,
+        script: MetaTextInput::Simple(String::new()),
+        deps: None,
+        env: None,
+    };
+    let result = MiqResult::create("empty1", &empty1);
+    assert_eq!(result.as_str(), "empty1-pfx2ab3qq61c3f8hxipz7qi2s2d16zaj");
+
+    let empty0 = PackageInput {
+        name: "empty0".into(),
+        version: None,
+        script: MetaTextInput::Simple("    set -x\n    pwd\n    ls -la\n    sleep 10\n  ".into()),
+        deps: None,
+        env: None,
+    };
+    let result = MiqResult::create("empty0", &empty0);
+    assert_eq!(result.as_str(), "empty0-3lwayxj1vl7p8fps9mjm7yhba9gxs9x2");
+}
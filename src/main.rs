@@ -2,29 +2,50 @@
 #[macro_use]
 extern crate educe;
 
+mod archive;
 mod build;
+mod build_env;
 mod build_fetch;
 mod build_package;
 mod busybox;
+mod cache;
+mod config;
+mod copy;
+mod daemon;
 mod db;
+mod diff_closures;
+mod disk;
+mod du;
 mod eval;
+mod export;
+mod gc;
+mod hash;
+mod layout;
+mod lock;
 mod lua;
+mod lua_env;
 mod lua_fetch;
 mod lua_package;
 mod mem_app;
+mod optimise;
+mod prefetch;
+mod profile;
+mod refscan;
+mod rootless;
 mod schema_db;
 mod schema_eval;
-#[cfg(no)]
+#[cfg(any())]
 mod semaphore;
+mod signing;
+mod verify;
 
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use ambassador::{delegatable_trait, Delegate};
 use clap::Parser;
 use color_eyre::eyre::{bail, eyre};
-use color_eyre::Result;
-use file_lock::{FileLock, FileOptions};
+use color_eyre::{Help, Result};
 use tracing::info;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
@@ -55,32 +76,34 @@ fn setup_logging() -> Result<()> {
 
 fn main() -> Result<()> {
     setup_logging()?;
+    config::load()?;
 
-    check_dirs()?;
+    let parsed = CliParser::parse();
 
-    let _lock = match FileLock::lock(
-        "/miq/lock",
-        false,
-        FileOptions::new().write(true).append(true).create(true),
-    ) {
-        Ok(inner) => inner,
-        Err(err) => match err.kind() {
-            io::ErrorKind::WouldBlock => {
-                bail!("Another miq process is holding /miq/lock, aborting!")
-            }
-            _ => bail!(eyre!(err).wrap_err("Checking if another process holds /miq/lock")),
-        },
-    };
+    let rootless = parsed.rootless
+        || config::get().rootless
+        || matches!(parsed.command, MiqCommands::Enter(_));
+    if rootless {
+        rootless::enter(parsed.store.clone().or_else(|| config::get().store.clone()))?;
+        layout::init(Some(PathBuf::from(layout::DEFAULT_ROOT)))?;
+    } else {
+        layout::init(parsed.store.clone())?;
+    }
+
+    check_dirs()?;
 
-    let parsed = CliParser::parse();
     parsed.command.main()
 }
 
 fn check_dirs() -> Result<()> {
-    if !PathBuf::from("/miq").try_exists()? {
+    let layout = layout::get();
+    let root = layout.root();
+
+    if root == Path::new(layout::DEFAULT_ROOT) && !root.try_exists()? {
         info!("Create /miq?");
         if !dialoguer::Confirm::new().default(false).interact()? {
-            bail!("No confirmation");
+            return Err(eyre!("No confirmation")
+                .suggestion("Without sudo, use --rootless or set rootless = true in the config"));
         };
 
         std::process::Command::new("sudo")
@@ -98,10 +121,16 @@ fn check_dirs() -> Result<()> {
         cmd.output()?;
     };
 
-    for folder in ["/miq/store", "/miq/eval", "/miq/log"] {
-        if !PathBuf::from(folder).try_exists()? {
+    for folder in [
+        layout.store_dir(),
+        layout.eval_dir(),
+        layout.log_dir(),
+        layout.gcroots_dir(),
+        layout.gcroots_auto_dir(),
+    ] {
+        if !folder.try_exists()? {
             info!(?folder, "Creating directory");
-            std::fs::create_dir(folder)?;
+            std::fs::create_dir_all(folder)?;
         };
     }
 
@@ -129,6 +158,14 @@ pub trait Main {
 pub struct CliParser {
     #[command(subcommand)]
     pub command: MiqCommands,
+
+    /// Root of the store, which holds the store paths, the database and the logs
+    #[arg(long, global = true, env = "MIQ_STORE", value_hint = clap::ValueHint::DirPath)]
+    pub store: Option<PathBuf>,
+
+    /// Keep the store in ~/.local/share/miq, or --store, and mount it at /miq in a namespace
+    #[arg(long, global = true)]
+    pub rootless: bool,
 }
 
 #[derive(clap::Subcommand, Debug, Delegate)]
@@ -139,4 +176,11 @@ pub enum MiqCommands {
     Lua(crate::lua::Args),
     Store(crate::db::Args),
     Schema(crate::schema_eval::Args),
+    Prefetch(crate::prefetch::Args),
+    Gc(crate::gc::Args),
+    Copy(crate::copy::Args),
+    Key(crate::signing::Args),
+    Daemon(crate::daemon::Args),
+    Enter(crate::rootless::Args),
+    Profile(crate::profile::Args),
 }
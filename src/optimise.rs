@@ -0,0 +1,250 @@
+//! Deduplicate the store by hard-linking files with identical contents.
+//!
+//! Every regular file is hashed, and linked from `.links/<hash>` in the store directory. If that
+//! link already exists, the file is replaced by a hard link to it. Each step leaves the store
+//! consistent, so an interrupted run is picked up by the next one.
+
+use std::fs::{File, Permissions};
+use std::io::Read;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use indicatif::HumanBytes;
+use tracing::{debug, trace, warn};
+
+use crate::db::DbConnection;
+
+/// Directory of the links, which is skipped when looking for unregistered paths
+pub fn links_dir() -> PathBuf {
+    crate::layout::get().store_dir().join(".links")
+}
+
+/// Prefix of the temporary links, which are renamed over the original file
+const TEMP_PREFIX: &str = ".tmp-link-";
+
+#[derive(Debug, clap::Args)]
+/// Replace identical files in the store with hard links
+pub struct Args {}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        let _lock = crate::lock::StoreLock::shared()?;
+        let conn = DbConnection::new()?;
+        let mut report = OptimiseReport::default();
+
+        clean_temp_links(&links_dir())?;
+        for path in conn.list()? {
+            optimise_path(&path.store_path, &mut report)?;
+        }
+
+        println!(
+            "{} files linked, {} saved",
+            report.linked,
+            HumanBytes(report.saved)
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct OptimiseReport {
+    pub linked: u64,
+    pub saved: u64,
+}
+
+/// Remove the temporary links left behind by an interrupted run
+fn clean_temp_links(links: &Path) -> Result<()> {
+    let entries = match std::fs::read_dir(links) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .as_encoded_bytes()
+            .starts_with(TEMP_PREFIX.as_bytes())
+        {
+            debug!(path = ?entry.path(), "Removing stale temporary link");
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).wrap_err(format!("Opening {:?}", path))?;
+    let mut hasher = crate::hash::IntegrityHasher::default();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(crate::hash::to_base32(hasher.finish().digest()))
+}
+
+/// Run `f` with `dir` writable, restoring its permissions afterwards
+fn with_writable_dir<T>(dir: &Path, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let mode = std::fs::metadata(dir)?.permissions().mode();
+    if mode & 0o200 != 0 {
+        return f();
+    }
+
+    std::fs::set_permissions(dir, Permissions::from_mode(mode | 0o200))?;
+    let result = f();
+    std::fs::set_permissions(dir, Permissions::from_mode(mode))?;
+    result
+}
+
+fn optimise_file(
+    path: &Path,
+    meta: &std::fs::Metadata,
+    links: &Path,
+    report: &mut OptimiseReport,
+) -> Result<()> {
+    let hash = hash_file(path)?;
+    let link = links.join(&hash);
+
+    let link_meta = match std::fs::symlink_metadata(&link) {
+        Ok(link_meta) => link_meta,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            trace!(?path, ?link, "New link");
+            match std::fs::hard_link(path, &link) {
+                Ok(()) => return Ok(()),
+                // Too many links, or another run created it
+                Err(err) => {
+                    debug!(?path, ?err, "Couldn't create link");
+                    return Ok(());
+                }
+            }
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    if link_meta.ino() == meta.ino() {
+        trace!(?path, "Already linked");
+        return Ok(());
+    }
+
+    // A hard link shares the permissions, so they must already be equal
+    if link_meta.permissions().mode() != meta.permissions().mode() {
+        trace!(?path, "Permissions differ, skipping");
+        return Ok(());
+    }
+
+    let dir = path.parent().unwrap();
+    let temp = links.join(format!("{}{}", TEMP_PREFIX, std::process::id()));
+
+    // Left over if a previous process with the same pid was interrupted
+    crate::build::clean_path(&temp)?;
+    if let Err(err) = std::fs::hard_link(&link, &temp) {
+        warn!(?path, ?err, "Couldn't link");
+        return Ok(());
+    }
+
+    with_writable_dir(dir, || {
+        std::fs::rename(&temp, path).wrap_err(format!("Replacing {:?}", path))?;
+        Ok(())
+    })?;
+
+    debug!(?path, ?link, "Linked");
+    report.linked += 1;
+    // Only free if this was the last link to the old contents
+    if meta.nlink() == 1 {
+        report.saved += meta.len();
+    }
+
+    Ok(())
+}
+
+fn optimise_tree(path: &Path, links: &Path, report: &mut OptimiseReport) -> Result<()> {
+    let meta = std::fs::symlink_metadata(path)?;
+
+    if meta.is_dir() {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(path)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<_>>()?;
+        entries.sort();
+        for entry in entries {
+            optimise_tree(&entry, links, report)?;
+        }
+    } else if meta.is_file() && meta.len() > 0 {
+        optimise_file(path, &meta, links, report)?;
+    }
+
+    Ok(())
+}
+
+/// Hard-link the files of a registered store path with identical files in the store
+pub fn optimise_path<P: AsRef<Path>>(path: P, report: &mut OptimiseReport) -> Result<()> {
+    let links = links_dir();
+    std::fs::create_dir_all(&links)?;
+    let path = crate::layout::get().physical(path);
+    optimise_tree(&path, &links, report).wrap_err(format!("Optimising {:?}", path))
+}
+
+/// Remove the links that no store path uses anymore, returning the bytes freed
+pub fn remove_unused_links() -> Result<u64> {
+    let entries = match std::fs::read_dir(links_dir()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut freed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.nlink() == 1 {
+            trace!(path = ?entry.path(), "Removing unused link");
+            std::fs::remove_file(entry.path())?;
+            freed += meta.len();
+        }
+    }
+
+    Ok(freed)
+}
+
+#[test]
+fn test_optimise_tree() {
+    let dir = tempfile::tempdir().unwrap();
+    let links = dir.path().join(".links");
+    std::fs::create_dir(&links).unwrap();
+
+    let a = dir.path().join("a");
+    let b = dir.path().join("b");
+    for path in [&a, &b] {
+        std::fs::create_dir(path).unwrap();
+        std::fs::write(path.join("file"), "hello world").unwrap();
+        std::fs::write(path.join("unique"), path.to_str().unwrap()).unwrap();
+    }
+    std::fs::set_permissions(&b, Permissions::from_mode(0o555)).unwrap();
+
+    let mut report = OptimiseReport::default();
+    optimise_tree(&a, &links, &mut report).unwrap();
+    optimise_tree(&b, &links, &mut report).unwrap();
+    assert_eq!(report.linked, 1);
+    assert_eq!(report.saved, 11);
+
+    let ino = |p: PathBuf| std::fs::metadata(p).unwrap().ino();
+    assert_eq!(ino(a.join("file")), ino(b.join("file")));
+    assert_ne!(ino(a.join("unique")), ino(b.join("unique")));
+    let mode = std::fs::metadata(&b).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o555);
+
+    // Running again does nothing
+    let mut report = OptimiseReport::default();
+    optimise_tree(&b, &links, &mut report).unwrap();
+    assert_eq!(report.linked, 0);
+
+    std::fs::set_permissions(&b, Permissions::from_mode(0o755)).unwrap();
+}
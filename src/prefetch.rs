@@ -0,0 +1,102 @@
+use std::fs::Permissions;
+use std::os::unix::prelude::PermissionsExt;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use indicatif::ProgressBar;
+use tracing::{debug, info};
+use url::Url;
+
+use crate::db::DbConnection;
+use crate::lock::{PathLock, StoreLock};
+use crate::lua_fetch::fixed_output_result;
+use crate::schema_eval::{Fetch, Unit};
+
+#[derive(Debug, clap::Args)]
+/// Download a file into the store and print its hash
+pub struct Args {
+    /// URL to download, file:// URLs are read from disk
+    url: Url,
+
+    /// Mark the file as executable
+    #[arg(long, short)]
+    executable: bool,
+
+    /// Name of the store path, defaults to the last segment of the URL
+    #[arg(long, short)]
+    name: Option<String>,
+}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        tokio::runtime::Runtime::new()?.block_on(self._main())
+    }
+}
+
+impl Args {
+    async fn _main(&self) -> Result<()> {
+        let _lock = StoreLock::shared()?;
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .url
+                .path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .filter(|segment| !segment.is_empty())
+                .ok_or_else(|| eyre!("Can't guess a name from {}, use --name", self.url))?
+                .to_owned(),
+        };
+
+        let temp = crate::build::store_tempdir("prefetch")?;
+        let out = temp.path().join("out");
+
+        let pb = ProgressBar::new_spinner();
+        pb.set_message(name.clone());
+        let integrity = crate::build_fetch::download(self.url.as_str(), &out, &pb).await?;
+        pb.finish_and_clear();
+
+        let result = fixed_output_result(&name, &integrity, self.executable);
+        let unit = Unit::FetchUnit(Fetch {
+            result: result.clone(),
+            name,
+            url: self.url.to_string(),
+            integrity: Some(integrity),
+            executable: self.executable,
+        });
+        unit.write_to_disk()?;
+
+        let path = result.store_path();
+        let mut conn = DbConnection::new()?;
+        let _path_lock = PathLock::lock(&result)?;
+
+        if conn.is_db_path(&path)? {
+            debug!(?path, "Already in the store");
+        } else {
+            let perm = if self.executable {
+                Permissions::from_mode(0o555)
+            } else {
+                Permissions::from_mode(0o444)
+            };
+            std::fs::set_permissions(&out, perm)?;
+
+            crate::build::clean_path(crate::layout::get().physical(&path))?;
+            conn.add_from(&out, &path, Vec::new())?;
+        }
+
+        info!("{}", path.to_string_lossy());
+        println!("{}", integrity);
+        println!();
+        println!("miq.fetch {{");
+        println!("\turl = \"{}\",", self.url);
+        if let Some(name) = &self.name {
+            println!("\tname = \"{}\",", name);
+        }
+        if self.executable {
+            println!("\texecutable = true,");
+        }
+        println!("\thash = \"{}\",", integrity);
+        println!("}}");
+
+        Ok(())
+    }
+}
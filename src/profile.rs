@@ -0,0 +1,443 @@
+//! User profiles, to put the outputs of units on the `PATH`.
+//!
+//! Each user has a directory under `profiles` in the root of the store, with numbered generations:
+//! `generation-<n>` links to a store path that merges the `bin`, `lib` and `share` directories of
+//! the installed paths with symlinks, and `current` links to one of the generations. Adding
+//! `profiles/<user>/current/bin` to the `PATH` makes the installed tools available.
+//!
+//! Every generation is a GC root, and switching between them replaces `current` atomically.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use color_eyre::eyre::{bail, ensure, eyre, Context};
+use color_eyre::{Help, Result};
+use nix::unistd::{Uid, User};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::build_env::SymlinkForest;
+use crate::db::DbConnection;
+use crate::eval::{MiqResult, RefToUnit, UnitRef};
+use crate::gc::TempRoots;
+use crate::hash::HASH_STORE_DIR;
+use crate::lock::{PathLock, StoreLock};
+
+const CURRENT_LINK: &str = "current";
+const GENERATION_PREFIX: &str = "generation-";
+/// File at the root of a generation, listing the paths installed in it
+const MANIFEST: &str = "manifest.json";
+/// Directories of the installed paths that are merged into a generation
+const MERGED_DIRS: [&str; 3] = ["bin", "lib", "share"];
+
+#[derive(Debug, clap::Args)]
+/// Manage the tools installed in your profile
+pub struct Args {
+    #[command(subcommand)]
+    action: ProfileSubcommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum ProfileSubcommand {
+    /// Build units, and create a generation with them added
+    Install(InstallArgs),
+    /// Create a generation without some of the installed paths
+    #[command(visible_alias("rm"))]
+    Remove(RemoveArgs),
+    /// List the generations and the paths installed in them
+    #[command(visible_alias("ls"))]
+    List,
+    /// Switch to the generation before the current one
+    Rollback,
+    /// Switch to another generation
+    SwitchGeneration(SwitchGenerationArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct InstallArgs {
+    /// Unitrefs to build and install, or store paths. They replace installed paths with the same
+    /// name.
+    #[arg(required = true)]
+    targets: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct RemoveArgs {
+    /// Names of the installed paths, without their hash
+    #[arg(required = true)]
+    names: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct SwitchGenerationArgs {
+    /// Number of the generation, as shown by miq profile list
+    generation: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    paths: Vec<String>,
+}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        let profile = Profile::for_current_user()?;
+
+        match &self.action {
+            ProfileSubcommand::Install(args) => {
+                // The targets are rooted until the generation links to them
+                let _lock = StoreLock::shared()?;
+                let mut roots = Vec::new();
+                let mut paths = profile.current_paths()?;
+                for target in &args.targets {
+                    let (path, target_roots) = resolve(target)?;
+                    roots.push(target_roots);
+                    let name = path_name(&path)?;
+                    paths.retain(|p| path_name(p).ok().as_deref() != Some(name.as_str()));
+                    paths.push(path);
+                }
+
+                let generation = profile.create_generation(paths)?;
+                println!("Switched to generation {}", generation);
+
+                let bin = profile.dir.join(CURRENT_LINK).join("bin");
+                let path_var = std::env::var_os("PATH").unwrap_or_default();
+                if !std::env::split_paths(&path_var).any(|p| p == bin) {
+                    println!(
+                        "Add {} to your PATH to use the installed tools",
+                        bin.display()
+                    );
+                }
+            }
+            ProfileSubcommand::Remove(args) => {
+                let mut paths = profile.current_paths()?;
+                for name in &args.names {
+                    let before = paths.len();
+                    paths.retain(|p| path_name(p).ok().as_deref() != Some(name.as_str()));
+                    if paths.len() == before {
+                        return Err(eyre!("{} is not installed", name)
+                            .suggestion("See what is installed with miq profile list"));
+                    }
+                }
+
+                let generation = profile.create_generation(paths)?;
+                println!("Switched to generation {}", generation);
+            }
+            ProfileSubcommand::List => {
+                let current = profile.current()?;
+                for (number, link) in profile.generations()? {
+                    let marker = if Some(number) == current { "*" } else { " " };
+                    let created = std::fs::symlink_metadata(&link)?.modified()?;
+                    let names: Vec<String> = read_manifest(&link)?
+                        .paths
+                        .iter()
+                        .map(|p| path_name(p))
+                        .collect::<Result<_>>()?;
+                    println!(
+                        "{:>4} {} {}  {}",
+                        number,
+                        marker,
+                        humantime::format_rfc3339_seconds(created),
+                        names.join(" ")
+                    );
+                }
+            }
+            ProfileSubcommand::Rollback => {
+                let previous = profile.rollback()?;
+                println!("Switched to generation {}", previous);
+            }
+            ProfileSubcommand::SwitchGeneration(args) => {
+                profile.switch(args.generation)?;
+                println!("Switched to generation {}", args.generation);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Profile {
+    dir: PathBuf,
+}
+
+impl Profile {
+    fn for_current_user() -> Result<Self> {
+        let uid = Uid::current();
+        let name = match User::from_uid(uid)? {
+            Some(user) => user.name,
+            None => uid.to_string(),
+        };
+
+        let dir = crate::layout::get().profiles_dir().join(name);
+        std::fs::create_dir_all(&dir).wrap_err(format!("Creating {:?}", dir))?;
+        Ok(Self { dir })
+    }
+
+    fn generation_link(&self, number: u32) -> PathBuf {
+        self.dir.join(format!("{}{}", GENERATION_PREFIX, number))
+    }
+
+    /// Links of the generations, by number
+    fn generations(&self) -> Result<BTreeMap<u32, PathBuf>> {
+        generations_in(&self.dir)
+    }
+
+    fn current(&self) -> Result<Option<u32>> {
+        let target = match std::fs::read_link(self.dir.join(CURRENT_LINK)) {
+            Ok(target) => target,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => bail!(err),
+        };
+
+        let number = target
+            .to_str()
+            .and_then(|name| name.strip_prefix(GENERATION_PREFIX))
+            .and_then(|number| number.parse().ok())
+            .ok_or_else(|| eyre!("{:?} is not a generation", target))?;
+        Ok(Some(number))
+    }
+
+    /// Paths installed in the current generation
+    fn current_paths(&self) -> Result<Vec<String>> {
+        match self.current()? {
+            Some(number) => Ok(read_manifest(&self.generation_link(number))?.paths),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Point `current` to a generation, by renaming a new link over it
+    fn switch(&self, number: u32) -> Result<()> {
+        let link = self.generation_link(number);
+        ensure!(
+            std::fs::symlink_metadata(&link).is_ok(),
+            "Generation {} doesn't exist",
+            number
+        );
+
+        let temp = self
+            .dir
+            .join(format!("{}{}", crate::build::TEMP_PREFIX, CURRENT_LINK));
+        crate::build::clean_path(&temp)?;
+        std::os::unix::fs::symlink(link.file_name().unwrap(), &temp)?;
+        std::fs::rename(&temp, self.dir.join(CURRENT_LINK))?;
+
+        debug!(?link, "Switched generation");
+        Ok(())
+    }
+
+    /// Switch to the generation before the current one
+    fn rollback(&self) -> Result<u32> {
+        let current = self
+            .current()?
+            .ok_or_else(|| eyre!("The profile has no generations"))?;
+        let Some(previous) = self.generations()?.into_keys().rfind(|n| *n < current) else {
+            bail!("There is no generation before {}", current);
+        };
+        self.switch(previous)?;
+        Ok(previous)
+    }
+
+    /// Create a generation with `paths` installed, and switch to it
+    fn create_generation(&self, mut paths: Vec<String>) -> Result<u32> {
+        paths.sort();
+        paths.dedup();
+
+        let _lock = StoreLock::shared()?;
+        let mut conn = DbConnection::new()?;
+        let result = MiqResult::create("profile", &paths);
+        // Until the generation links to it, the path is not rooted
+        let _roots = TempRoots::new(
+            paths
+                .iter()
+                .map(Path::new)
+                .chain([result.store_path().as_path()]),
+        )?;
+        let path = create_generation_path(&mut conn, &result, &paths)?;
+
+        // The store lock is shared, so another install can take the same number first
+        let (number, link) = loop {
+            let number = self.generations()?.into_keys().last().unwrap_or(0) + 1;
+            let link = self.generation_link(number);
+            match std::os::unix::fs::symlink(crate::layout::get().physical(&path), &link) {
+                Ok(()) => break (number, link),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    debug!(?link, "Generation was taken, retrying");
+                }
+                Err(err) => return Err(err).wrap_err(format!("Creating {:?}", link)),
+            }
+        };
+        info!(?link, ?path, "Created generation");
+
+        self.switch(number)?;
+        Ok(number)
+    }
+}
+
+fn generations_in(dir: &Path) -> Result<BTreeMap<u32, PathBuf>> {
+    let mut result = BTreeMap::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(GENERATION_PREFIX))
+            .and_then(|number| number.parse().ok());
+        if let Some(number) = number {
+            result.insert(number, entry.path());
+        }
+    }
+
+    Ok(result)
+}
+
+/// Store paths of the generations of every profile, which are GC roots
+pub fn roots() -> Result<BTreeSet<PathBuf>> {
+    let layout = crate::layout::get();
+    let mut roots = BTreeSet::new();
+
+    let entries = match std::fs::read_dir(layout.profiles_dir()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(roots),
+        Err(err) => bail!(err),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        for link in generations_in(&entry.path())?.into_values() {
+            roots.insert(layout.logical(std::fs::read_link(&link)?));
+        }
+    }
+
+    Ok(roots)
+}
+
+fn read_manifest(generation: &Path) -> Result<Manifest> {
+    let path = generation.join(MANIFEST);
+    let text = std::fs::read_to_string(&path).wrap_err(format!("Reading {:?}", path))?;
+    Ok(serde_json::from_str(&text)?)
+}
+
+/// Name of a store path, without its hash
+fn path_name(path: &str) -> Result<String> {
+    let result =
+        MiqResult::from_store_path(path).ok_or_else(|| eyre!("{:?} is not a store path", path))?;
+    Ok(result.name().to_owned())
+}
+
+/// Build a unitref, or check that a store path is registered. The store path is rooted until the
+/// returned [TempRoots] is dropped, from before it is built.
+fn resolve(target: &str) -> Result<(String, TempRoots)> {
+    let path = crate::db::normalize_store_path(target);
+    if path.starts_with(HASH_STORE_DIR) {
+        let roots = TempRoots::new([&path])?;
+        ensure!(
+            DbConnection::new()?.is_db_path(&path)?,
+            "{:?} is not in the store",
+            path
+        );
+        return Ok((path.to_str().unwrap().to_owned(), roots));
+    }
+
+    let unit_ref = UnitRef::from_str(target)?;
+    let unit = unit_ref.ref_to_unit()?;
+    let path = unit.result().store_path().to_str().unwrap().to_owned();
+    let roots = TempRoots::new([&path])?;
+    crate::build::build_unit_ref(unit_ref, unit).wrap_err(format!("Building {}", target))?;
+    Ok((path, roots))
+}
+
+/// Register the store path of `result`, merging `paths`, if it's not registered already
+fn create_generation_path(
+    conn: &mut DbConnection,
+    result: &MiqResult,
+    paths: &[String],
+) -> Result<PathBuf> {
+    let path = result.store_path().to_path_buf();
+
+    let _lock = PathLock::lock(result)?;
+    if conn.is_db_path(&path)? {
+        return Ok(path);
+    }
+
+    let temp = crate::build::store_tempdir("profile")?;
+    let out = temp.path().join("out");
+    std::fs::create_dir(&out)?;
+
+    let mut forest = SymlinkForest::new(&out, false);
+    for installed in paths {
+        for dir in MERGED_DIRS {
+            let logical = Path::new(installed).join(dir);
+            if crate::layout::get().physical(&logical).is_dir() {
+                forest
+                    .add(&logical, Path::new(dir))
+                    .suggestion("Remove one of them from the profile")?;
+            }
+        }
+    }
+
+    let manifest = Manifest {
+        paths: paths.to_vec(),
+    };
+    std::fs::write(out.join(MANIFEST), serde_json::to_vec_pretty(&manifest)?)?;
+
+    crate::build::clean_path(crate::layout::get().physical(&path))?;
+    conn.add_from(&out, &path, paths.to_vec())?;
+    Ok(path)
+}
+
+#[test]
+fn test_generations() {
+    let _store = crate::layout::test_store();
+    let mut conn = DbConnection::new().unwrap();
+    let layout = crate::layout::get();
+
+    let mut installed = Vec::new();
+    for name in ["hello", "world"] {
+        let path = MiqResult::create(name, &"profile-test").store_path();
+        let bin = layout.physical(&path).join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        std::fs::write(bin.join(name), name).unwrap();
+        conn.add(&path).unwrap();
+        installed.push(path.to_str().unwrap().to_owned());
+    }
+
+    let dir = layout.profiles_dir().join("test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let profile = Profile { dir };
+
+    assert_eq!(
+        profile.create_generation(installed[..1].to_vec()).unwrap(),
+        1
+    );
+    assert_eq!(profile.create_generation(installed.clone()).unwrap(), 2);
+    assert_eq!(profile.current().unwrap(), Some(2));
+    assert_eq!(profile.current_paths().unwrap(), installed);
+    let bin = profile.dir.join(CURRENT_LINK).join("bin");
+    let world = Path::new(&installed[1]).join("bin/world");
+    assert_eq!(std::fs::read_link(bin.join("world")).unwrap(), world);
+
+    let generation = layout.logical(std::fs::read_link(profile.generation_link(2)).unwrap());
+    assert!(roots().unwrap().contains(&generation));
+
+    assert_eq!(profile.rollback().unwrap(), 1);
+    assert_eq!(profile.current_paths().unwrap(), installed[..1]);
+    assert!(std::fs::symlink_metadata(bin.join("world")).is_err());
+    assert!(profile.rollback().is_err());
+
+    profile.switch(2).unwrap();
+    assert_eq!(profile.current().unwrap(), Some(2));
+    assert!(profile.switch(3).is_err());
+
+    // A generation that another install just created is skipped
+    std::os::unix::fs::symlink("taken", profile.generation_link(3)).unwrap();
+    assert_eq!(
+        profile.create_generation(installed[1..].to_vec()).unwrap(),
+        4
+    );
+    assert_eq!(profile.current_paths().unwrap(), installed[1..]);
+}
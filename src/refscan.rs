@@ -0,0 +1,145 @@
+//! Scan store paths for references to other store paths.
+//!
+//! Like Nix, a reference is any occurrence of the hash part of a candidate path, either in the
+//! contents of a file, the target of a symlink or the name of a directory entry.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use tracing::trace;
+
+use crate::eval::MiqResult;
+use crate::hash::HASH_PART_LEN;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+struct Scanner<'c> {
+    hashes: HashMap<&'c [u8], &'c MiqResult>,
+    found: BTreeSet<MiqResult>,
+}
+
+impl<'c> Scanner<'c> {
+    fn new(candidates: &'c BTreeSet<MiqResult>) -> Self {
+        let hashes = candidates
+            .iter()
+            .map(|result| (result.hash_part().as_bytes(), result))
+            .collect();
+
+        Self {
+            hashes,
+            found: BTreeSet::new(),
+        }
+    }
+
+    /// Look for hashes in `buf`. Windows are skipped as soon as they contain a byte outside of
+    /// the base32 alphabet, checking from the end so most bytes are only visited once.
+    fn scan(&mut self, buf: &[u8]) {
+        let mut i = 0;
+        while i + HASH_PART_LEN <= buf.len() {
+            let window = &buf[i..i + HASH_PART_LEN];
+            match window.iter().rposition(|b| !crate::hash::is_base32(*b)) {
+                Some(j) => i += j + 1,
+                None => {
+                    if let Some(result) = self.hashes.get(window) {
+                        trace!(?result, "Found reference");
+                        self.found.insert((*result).clone());
+                    }
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    fn scan_file(&mut self, path: &Path) -> Result<()> {
+        let mut file = File::open(path).wrap_err(format!("Opening {:?}", path))?;
+        // Keep the tail of the previous chunk, so that hashes across chunks are found
+        let mut buf = vec![0; HASH_PART_LEN + CHUNK_SIZE];
+        let mut carry = 0;
+
+        loop {
+            let n = file.read(&mut buf[carry..])?;
+            if n == 0 {
+                break;
+            }
+            let len = carry + n;
+            self.scan(&buf[..len]);
+
+            carry = len.min(HASH_PART_LEN - 1);
+            buf.copy_within(len - carry..len, 0);
+        }
+
+        Ok(())
+    }
+
+    fn scan_path(&mut self, path: &Path) -> Result<()> {
+        let meta = std::fs::symlink_metadata(path)?;
+
+        if let Some(name) = path.file_name() {
+            self.scan(name.as_encoded_bytes());
+        }
+
+        if meta.is_symlink() {
+            let target = std::fs::read_link(path)?;
+            self.scan(target.as_os_str().as_encoded_bytes());
+        } else if meta.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                self.scan_path(&entry?.path())?;
+            }
+        } else if meta.is_file() {
+            self.scan_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Find which of `candidates` are referenced from `path`
+#[tracing::instrument(skip(candidates), ret, err, level = "debug")]
+pub fn scan_references<P: AsRef<Path> + std::fmt::Debug>(
+    path: P,
+    candidates: &BTreeSet<MiqResult>,
+) -> Result<BTreeSet<MiqResult>> {
+    let mut scanner = Scanner::new(candidates);
+    scanner.scan_path(path.as_ref())?;
+    Ok(scanner.found)
+}
+
+#[test]
+fn test_scan_references() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = MiqResult::create("a", &"a");
+    let b = MiqResult::create("b", &"b");
+    let c = MiqResult::create("c", &"c");
+    let candidates = BTreeSet::from([a.clone(), b.clone(), c.clone()]);
+
+    std::fs::create_dir(dir.path().join("bin")).unwrap();
+    std::fs::write(
+        dir.path().join("bin").join("hello"),
+        format!("#!{}/bin/bash\n", a.store_path().to_string_lossy()),
+    )
+    .unwrap();
+    std::os::unix::fs::symlink(b.store_path(), dir.path().join("link")).unwrap();
+
+    let found = scan_references(dir.path(), &candidates).unwrap();
+    assert_eq!(found, BTreeSet::from([a, b]));
+}
+
+#[test]
+fn test_scan_across_chunks() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = MiqResult::create("a", &"a");
+    let candidates = BTreeSet::from([a.clone()]);
+
+    let mut contents = vec![b'-'; CHUNK_SIZE + HASH_PART_LEN - 10];
+    contents.extend_from_slice(a.hash_part().as_bytes());
+    contents.extend_from_slice(&[b'-'; 100]);
+    let file = dir.path().join("data");
+    std::fs::write(&file, contents).unwrap();
+
+    let found = scan_references(&file, &candidates).unwrap();
+    assert_eq!(found, BTreeSet::from([a]));
+}
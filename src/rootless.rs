@@ -0,0 +1,194 @@
+//! Rootless mode, for users that can't create `/miq`.
+//!
+//! The store is kept under `~/.local/share/miq`, and every command runs in a user and mount
+//! namespace where it's mounted at `/miq`. Since `/` can't be written to, the namespace gets a new
+//! root on a tmpfs, with a bind mount of every entry of the real `/` next to `/miq`.
+//!
+//! The new root is assembled in `.rootfs`, inside the real root of the store, so it lives in a
+//! directory the user owns and is the same for every command. It's only a mount point, and stays
+//! empty outside of the namespace.
+//!
+//! The namespace is entered with `pivot_root` rather than `chroot`, as a chrooted process can't
+//! create the user namespaces of the build sandbox.
+
+use std::ffi::OsString;
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::CloneFlags;
+use nix::unistd::{Gid, Uid};
+use tracing::{debug, warn};
+
+/// Set in the namespace, to the real root of the store, so that nested commands don't enter it
+/// again
+const NAMESPACE_ENV: &str = "MIQ_ROOTLESS_STORE";
+
+/// Directory in the real root of the store where the root of the namespace is assembled, see the
+/// module docs
+const ROOTFS_DIR: &str = ".rootfs";
+
+const NONE_NIX: Option<&str> = None;
+
+#[derive(Debug, clap::Args)]
+/// Open a shell where the rootless store is mounted at /miq
+pub struct Args {
+    /// Command to run instead of $SHELL
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    command: Vec<String>,
+}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        let (program, args) = match self.command.split_first() {
+            Some((program, args)) => (program.clone(), args),
+            None => (
+                std::env::var("SHELL").unwrap_or_else(|_| String::from("/bin/sh")),
+                &[][..],
+            ),
+        };
+
+        let err = std::process::Command::new(&program).args(args).exec();
+        Err(err).wrap_err(format!("Running {:?}", program))
+    }
+}
+
+/// Where the store is kept in rootless mode: `$XDG_DATA_HOME/miq`, or `~/.local/share/miq`
+pub fn data_dir() -> Result<PathBuf> {
+    data_dir_from(std::env::var_os("XDG_DATA_HOME"), std::env::var_os("HOME"))
+}
+
+fn data_dir_from(xdg_data_home: Option<OsString>, home: Option<OsString>) -> Result<PathBuf> {
+    if let Some(dir) = xdg_data_home {
+        return Ok(PathBuf::from(dir).join("miq"));
+    }
+    let home = home.ok_or_else(|| eyre!("$HOME is not set"))?;
+    Ok(PathBuf::from(home).join(".local/share/miq"))
+}
+
+#[test]
+fn test_data_dir() {
+    let xdg = Some(OsString::from("/data"));
+    let home = Some(OsString::from("/home/user"));
+    assert_eq!(
+        data_dir_from(xdg, home.clone()).unwrap(),
+        Path::new("/data/miq")
+    );
+    assert_eq!(
+        data_dir_from(None, home).unwrap(),
+        Path::new("/home/user/.local/share/miq")
+    );
+    assert!(data_dir_from(None, None).is_err());
+}
+
+/// Whether this process already runs in the namespace
+pub fn is_entered() -> bool {
+    std::env::var_os(NAMESPACE_ENV).is_some()
+}
+
+/// Move the process into a namespace where `store`, or the [data_dir], is mounted at `/miq`. This
+/// must run before any thread is started.
+pub fn enter(store: Option<PathBuf>) -> Result<()> {
+    if is_entered() {
+        debug!("Already in the rootless namespace");
+        return Ok(());
+    }
+
+    let store = match store {
+        Some(store) => std::path::absolute(store)?,
+        None => data_dir()?,
+    };
+    let rootfs = store.join(ROOTFS_DIR);
+    std::fs::create_dir_all(&rootfs).wrap_err(format!("Creating {:?}", rootfs))?;
+    let cwd = std::env::current_dir()?;
+
+    let uid = Uid::current();
+    let gid = Gid::current();
+    nix::sched::unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)
+        .wrap_err("Creating the user namespace")?;
+
+    // Keep the same ids, so that the files of the store belong to the user outside too
+    write_proc("uid_map", &format!("{} {} 1", uid, uid))?;
+    write_proc("setgroups", "deny")?;
+    write_proc("gid_map", &format!("{} {} 1", gid, gid))?;
+
+    mount(
+        NONE_NIX,
+        "/",
+        NONE_NIX,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        NONE_NIX,
+    )?;
+    mount(NONE_NIX, &rootfs, Some("tmpfs"), MsFlags::empty(), NONE_NIX)?;
+
+    for entry in std::fs::read_dir("/")? {
+        let entry = entry?;
+        if entry.file_name() == "miq" {
+            continue;
+        }
+        if let Err(err) = mirror_entry(&entry.path(), &rootfs.join(entry.file_name())) {
+            warn!(path = ?entry.path(), "Not available in the rootless namespace: {:#}", err);
+        }
+    }
+
+    let miq = rootfs.join("miq");
+    std::fs::create_dir(&miq)?;
+    mount(Some(&store), &miq, NONE_NIX, MsFlags::MS_BIND, NONE_NIX)?;
+
+    nix::unistd::chdir(&rootfs)?;
+    nix::unistd::pivot_root(".", ".")?;
+    umount2(".", MntFlags::MNT_DETACH)?;
+    nix::unistd::chdir(&cwd).wrap_err(format!("Entering {:?} in the namespace", cwd))?;
+
+    std::env::set_var(NAMESPACE_ENV, &store);
+    debug!(?store, "Entered the rootless namespace");
+    Ok(())
+}
+
+#[test]
+fn test_enter_nested() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = dir.path().join("store");
+
+    std::env::set_var(NAMESPACE_ENV, &store);
+    let result = enter(Some(store.clone()));
+    std::env::remove_var(NAMESPACE_ENV);
+
+    result.unwrap();
+    assert!(!store.exists(), "entered the namespace again");
+}
+
+fn write_proc(name: &str, contents: &str) -> Result<()> {
+    let path = Path::new("/proc/self").join(name);
+    std::fs::File::create(&path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .wrap_err(format!("Writing {:?}", path))
+}
+
+/// Make `path` from the real root available at `target` in the new one
+fn mirror_entry(path: &Path, target: &Path) -> Result<()> {
+    let meta = std::fs::symlink_metadata(path)?;
+
+    if meta.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(path)?, target)?;
+        return Ok(());
+    }
+
+    if meta.is_dir() {
+        std::fs::create_dir(target)?;
+    } else {
+        std::fs::File::create(target)?;
+    }
+    mount(
+        Some(path),
+        target,
+        NONE_NIX,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        NONE_NIX,
+    )?;
+
+    Ok(())
+}
@@ -1,7 +1,33 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    refs (referrer, reference) {
+        referrer -> Text,
+        reference -> Text,
+    }
+}
+
+diesel::table! {
+    signatures (store_path, signature) {
+        store_path -> Text,
+        signature -> Text,
+    }
+}
+
 diesel::table! {
     store (store_path) {
         store_path -> Text,
+        registered_at -> BigInt,
+        archive_hash -> Nullable<Text>,
+        archive_size -> Nullable<BigInt>,
     }
 }
+
+diesel::joinable!(refs -> store (referrer));
+diesel::joinable!(signatures -> store (store_path));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    refs,
+    signatures,
+    store,
+);
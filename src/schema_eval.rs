@@ -17,6 +17,7 @@ use tracing::info;
 
 use crate::db::DbConnection;
 use crate::eval::MiqResult;
+use crate::hash::Integrity;
 
 #[derive(Debug, clap::Args)]
 /// Generate the IR schema
@@ -39,8 +40,8 @@ impl crate::Main for Args {
             let schema = schema_for!(Unit);
             let schema_str = serde_json::to_string_pretty(&schema)?;
             println!("{}", &schema_str);
-            let p = "/miq/eval-schema.json";
-            info!("Writing schema to {}", p);
+            let p = crate::layout::get().eval_schema();
+            info!("Writing schema to {:?}", p);
             info!("Reset VS code with: rm ~/.config/Code/User/globalStorage/tamasfe.even-better-toml/*");
             std::fs::write(p, schema_str)?;
         } else {
@@ -61,7 +62,28 @@ pub trait Build {
         rebuild: bool,
         conn: &Mutex<DbConnection>,
         pb: ProgressBar,
-    ) -> Result<()>;
+    ) -> Result<BuildOutcome>;
+}
+
+/// How the output of a unit ended up in the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildOutcome {
+    /// It was already registered
+    Present,
+    /// It was downloaded from a substituter
+    Substituted,
+    /// It was built or fetched
+    Built,
+}
+
+impl std::fmt::Display for BuildOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildOutcome::Present => write!(f, "present"),
+            BuildOutcome::Substituted => write!(f, "substituted"),
+            BuildOutcome::Built => write!(f, "built"),
+        }
+    }
 }
 
 #[derive(Educe, PartialEq, Clone, Serialize, Deserialize, JsonSchema, Hash, Delegate, Eq)]
@@ -69,11 +91,15 @@ pub trait Build {
 #[educe(Debug)]
 // #[serde(untagged)]
 #[serde(tag = "type")]
+// The variant names are the tags in the unit files, so they can't drop the suffix
+#[allow(clippy::enum_variant_names)]
 pub enum Unit {
     #[educe(Debug(name = false))]
     PackageUnit(Package),
     #[educe(Debug(name = false))]
     FetchUnit(Fetch),
+    #[educe(Debug(name = false))]
+    EnvUnit(Env),
 }
 
 #[derive(Educe, PartialEq, Clone, Serialize, Deserialize, JsonSchema, Default, Hash, Eq)]
@@ -101,7 +127,23 @@ pub struct Fetch {
     #[educe(Debug(ignore))]
     pub url: String,
     #[educe(Debug(ignore))]
-    pub integrity: String,
+    #[schemars(with = "Option<String>")]
+    /// SRI hash that the downloaded file must match, like `sha256-<base64>`
+    pub integrity: Option<Integrity>,
     #[educe(Debug(ignore))]
     pub executable: bool,
 }
+
+/// Merges the trees of other units with symlinks, without running anything
+#[derive(Educe, PartialEq, Clone, Deserialize, Serialize, JsonSchema, Default, Hash, Eq)]
+#[educe(Debug)]
+pub struct Env {
+    #[educe(Debug(ignore))]
+    pub result: MiqResult,
+    pub name: String,
+    #[educe(Debug(ignore))]
+    pub paths: Vec<MiqResult>,
+    #[educe(Debug(ignore))]
+    /// Keep the file of the first path that provides it, instead of failing
+    pub ignore_collisions: bool,
+}
@@ -0,0 +1,337 @@
+//! Ed25519 signatures of store paths, to trust paths built on another machine.
+//!
+//! Keys and signatures are written as `<name>:<base64>`, where the name identifies the key, like
+//! `cache.example.com-1`. A signature covers the [fingerprint] of a path, so it vouches for its
+//! contents, its references and the unit that produced it.
+
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use color_eyre::eyre::{bail, ensure, eyre, Context};
+use color_eyre::{Help, Report, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use crate::export::PathMetadata;
+
+#[derive(Debug, clap::Args)]
+/// Manage the keys used to sign store paths
+pub struct Args {
+    #[command(subcommand)]
+    action: KeySubcommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum KeySubcommand {
+    /// Create a secret key, and print its public key
+    Generate(GenerateArgs),
+    /// Print the public key of a secret key
+    Public(PublicArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct GenerateArgs {
+    /// Name of the key, like cache.example.com-1
+    name: String,
+
+    /// Where to write the secret key
+    #[arg(long, short, value_hint = clap::ValueHint::FilePath)]
+    out: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+struct PublicArgs {
+    /// Secret key file
+    #[arg(value_hint = clap::ValueHint::FilePath)]
+    secret_key: PathBuf,
+}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        match &self.action {
+            KeySubcommand::Generate(args) => {
+                ensure!(
+                    !args.name.is_empty() && !args.name.contains(':'),
+                    "Key names can't be empty or contain ':'"
+                );
+
+                let mut seed = [0; 32];
+                std::fs::File::open("/dev/urandom")?.read_exact(&mut seed)?;
+                let key = SecretKey {
+                    name: args.name.clone(),
+                    key: SigningKey::from_bytes(&seed),
+                };
+
+                let mut file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&args.out)
+                    .wrap_err(format!("Creating {:?}", args.out))?;
+                writeln!(file, "{}", key)?;
+
+                println!("{}", key.public());
+            }
+            KeySubcommand::Public(args) => {
+                println!("{}", SecretKey::read(&args.secret_key)?.public());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn split_key(s: &str) -> Result<(&str, Vec<u8>)> {
+    let Some((name, encoded)) = s.trim().split_once(':') else {
+        bail!("{:?} is not of the form <name>:<base64>", s);
+    };
+    ensure!(!name.is_empty(), "{:?} has an empty name", s);
+    let decoded = BASE64
+        .decode(encoded)
+        .wrap_err(format!("Decoding {:?}", s))?;
+    Ok((name, decoded))
+}
+
+pub struct SecretKey {
+    pub name: String,
+    key: SigningKey,
+}
+
+impl SecretKey {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .wrap_err(format!("Reading secret key {:?}", path))?
+            .parse()
+            .wrap_err(format!("Parsing secret key {:?}", path))
+    }
+
+    pub fn public(&self) -> PublicKey {
+        PublicKey {
+            name: self.name.clone(),
+            key: self.key.verifying_key(),
+        }
+    }
+
+    /// Sign a path, returning the signature as `<name>:<base64>`
+    pub fn sign(&self, metadata: &PathMetadata) -> String {
+        let signature = self.key.sign(fingerprint(metadata).as_bytes());
+        format!("{}:{}", self.name, BASE64.encode(signature.to_bytes()))
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, bytes) = split_key(s)?;
+        let Ok(seed) = bytes.try_into() else {
+            bail!("Secret key {:?} is not 32 bytes long", name);
+        };
+        Ok(Self {
+            name: name.to_owned(),
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+}
+
+impl Display for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.name, BASE64.encode(self.key.to_bytes()))
+    }
+}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretKey({})", self.name)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct PublicKey {
+    pub name: String,
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    /// Whether `signature` was made by this key over `metadata`. Signatures by other keys are
+    /// never valid.
+    pub fn verify(&self, metadata: &PathMetadata, signature: &str) -> bool {
+        let Ok((name, bytes)) = split_key(signature) else {
+            return false;
+        };
+        let Ok(bytes) = <[u8; 64]>::try_from(bytes) else {
+            return false;
+        };
+
+        name == self.name
+            && self
+                .key
+                .verify_strict(
+                    fingerprint(metadata).as_bytes(),
+                    &Signature::from_bytes(&bytes),
+                )
+                .is_ok()
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, bytes) = split_key(s)?;
+        let Ok(bytes) = bytes.try_into() else {
+            bail!("Public key {:?} is not 32 bytes long", name);
+        };
+        let key = VerifyingKey::from_bytes(&bytes)
+            .map_err(|err| eyre!("Invalid public key {:?}: {}", name, err))?;
+        Ok(Self {
+            name: name.to_owned(),
+            key,
+        })
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = Report;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.name, BASE64.encode(self.key.to_bytes()))
+    }
+}
+
+impl std::fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+/// What a signature covers: the path, its archive, its references and the hash of its unit
+pub fn fingerprint(metadata: &PathMetadata) -> String {
+    let mut references = metadata.references.clone();
+    references.sort();
+
+    let mut result = format!(
+        "miq-path:1;{};{};{};{}",
+        metadata.store_path,
+        metadata.archive_hash,
+        metadata.archive_size,
+        references.join(",")
+    );
+    if let Some(eval_hash) = &metadata.eval_hash {
+        result.push_str(&format!(";eval:{}", eval_hash));
+    }
+    result
+}
+
+/// Names of the trusted keys that have validly signed the path
+pub fn trusted_signers(metadata: &PathMetadata) -> Vec<String> {
+    crate::config::get()
+        .trusted_public_keys
+        .iter()
+        .filter(|key| metadata.signatures.iter().any(|s| key.verify(metadata, s)))
+        .map(|key| key.name.clone())
+        .collect()
+}
+
+/// Fail unless a trusted key has signed the path, or signatures are not required
+pub fn check_trusted(metadata: &PathMetadata) -> Result<()> {
+    if !crate::config::get().require_sigs || !trusted_signers(metadata).is_empty() {
+        return Ok(());
+    }
+
+    Err(
+        eyre!("{:?} is not signed by a trusted key", metadata.store_path)
+            .suggestion("Add the public key of the signer to trusted-public-keys in the config"),
+    )
+}
+
+/// Sign the path with the secret keys in the config, adding the signatures that are new
+pub fn sign_with_config(metadata: &mut PathMetadata) -> Result<()> {
+    for path in &crate::config::get().secret_key_files {
+        let key = SecretKey::read(path)?;
+        let signature = key.sign(metadata);
+        if !metadata.signatures.contains(&signature) {
+            metadata.signatures.push(signature);
+        }
+    }
+    Ok(())
+}
+
+/// Key that the tests sign with, which their config trusts
+#[cfg(test)]
+pub fn test_key() -> SecretKey {
+    SecretKey {
+        name: "test-1".into(),
+        key: SigningKey::from_bytes(&[7; 32]),
+    }
+}
+
+#[cfg(test)]
+fn test_metadata() -> PathMetadata {
+    PathMetadata {
+        store_path: "/miq/store/foo-1w6jvy3gzfg8b13y0nf3gck56dfkqa5d".into(),
+        archive_hash: "sha256-uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek="
+            .parse()
+            .unwrap(),
+        archive_size: 128,
+        references: vec!["/miq/store/bar-647jb6fgw70zibalf8qsvc2ilq65wmra".into()],
+        signatures: vec![],
+        eval_hash: Some(
+            "sha256-LCa0a2j/xo/5m0U8HTBBNBNCLXBkg7+g+YpeiGJm564="
+                .parse()
+                .unwrap(),
+        ),
+    }
+}
+
+#[test]
+fn test_sign_verify() {
+    let key = test_key();
+    let other = SecretKey {
+        name: "test-1".into(),
+        key: SigningKey::from_bytes(&[8; 32]),
+    };
+
+    let metadata = test_metadata();
+    let signature = key.sign(&metadata);
+    assert!(signature.starts_with("test-1:"));
+    assert!(key.public().verify(&metadata, &signature));
+    assert!(!other.public().verify(&metadata, &signature));
+
+    let mut tampered = test_metadata();
+    tampered.references.clear();
+    assert!(!key.public().verify(&tampered, &signature));
+
+    let mut tampered = test_metadata();
+    tampered.eval_hash = None;
+    assert!(!key.public().verify(&tampered, &signature));
+
+    let renamed = signature.replacen("test-1", "test-2", 1);
+    assert!(!key.public().verify(&metadata, &renamed));
+}
+
+#[test]
+fn test_key_roundtrip() {
+    let key = test_key();
+
+    let parsed: SecretKey = key.to_string().parse().unwrap();
+    assert_eq!(parsed.public(), key.public());
+
+    let public: PublicKey = key.public().to_string().parse().unwrap();
+    assert_eq!(public, key.public());
+
+    assert!("test-1".parse::<PublicKey>().is_err());
+    assert!("test-1:aGVsbG8=".parse::<PublicKey>().is_err());
+}
@@ -0,0 +1,279 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Help, Result};
+use indicatif::ProgressBar;
+use tracing::{debug, info};
+
+use crate::db::{DbConnection, StorePath};
+use crate::eval::MiqResult;
+use crate::export::PathMetadata;
+use crate::schema_eval::{Build, Unit};
+
+#[derive(Debug, clap::Args)]
+#[command(group(clap::ArgGroup::new("target").required(true)))]
+/// Check that store paths still match the hash recorded when they were registered
+pub struct Args {
+    #[arg(value_hint = clap::ValueHint::DirPath, group = "target")]
+    /// Store path to verify
+    path: Option<PathBuf>,
+
+    /// Verify every registered path, and look for unregistered paths in the store
+    #[arg(long, group = "target")]
+    all: bool,
+
+    /// Rebuild or refetch the broken paths from their unit in the eval directory
+    #[arg(long)]
+    repair: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// Registered, but not on disk
+    Missing,
+    /// The archive hash doesn't match the recorded one
+    Mismatch { expected: String, got: String },
+    /// Couldn't be read to compute its hash
+    Unreadable(String),
+    /// On disk, but not registered
+    Unregistered,
+    /// Registered before hashes were recorded, so it can't be checked
+    NoHash,
+    /// Has a signature by a trusted key that doesn't match the path
+    BadSignature(String),
+    /// Not signed by any trusted key, while the config requires signatures
+    Unsigned,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing"),
+            Problem::Mismatch { expected, got } => {
+                write!(f, "hash mismatch, expected {}, got {}", expected, got)
+            }
+            Problem::Unreadable(err) => write!(f, "unreadable, {}", err),
+            Problem::Unregistered => write!(f, "not registered"),
+            Problem::NoHash => write!(f, "no hash recorded, skipping"),
+            Problem::BadSignature(key) => write!(f, "invalid signature by trusted key {}", key),
+            Problem::Unsigned => write!(f, "not signed by a trusted key"),
+        }
+    }
+}
+
+impl Problem {
+    /// Whether rebuilding the path can fix it
+    fn repairable(&self) -> bool {
+        !matches!(
+            self,
+            Problem::Unregistered | Problem::NoHash | Problem::Unsigned
+        )
+    }
+
+    fn is_failure(&self) -> bool {
+        !matches!(self, Problem::NoHash)
+    }
+}
+
+/// Re-hash a registered path and compare it with the hash recorded in the database
+pub fn verify_path(info: &StorePath) -> Option<Problem> {
+    let path = crate::layout::get().physical(&info.store_path);
+    let path = path.as_path();
+
+    if let Err(err) = std::fs::symlink_metadata(path) {
+        return Some(match err.kind() {
+            std::io::ErrorKind::NotFound => Problem::Missing,
+            _ => Problem::Unreadable(err.to_string()),
+        });
+    }
+
+    let Some(expected) = &info.archive_hash else {
+        return Some(Problem::NoHash);
+    };
+
+    match crate::archive::hash_path(path) {
+        Ok((got, _)) if got.to_string() == *expected => None,
+        Ok((got, _)) => Some(Problem::Mismatch {
+            expected: expected.clone(),
+            got: got.to_string(),
+        }),
+        Err(err) => Some(Problem::Unreadable(err.to_string())),
+    }
+}
+
+/// Check the signatures of a path made by trusted keys. Signatures by other keys are ignored,
+/// but one of the trusted keys must have signed the path if the config requires signatures.
+pub fn verify_signatures(metadata: &PathMetadata) -> Option<Problem> {
+    let config = crate::config::get();
+    let trusted = &config.trusted_public_keys;
+
+    for signature in &metadata.signatures {
+        let name = signature.split_once(':').map_or("", |(name, _)| name);
+        let Some(key) = trusted.iter().find(|key| key.name == name) else {
+            continue;
+        };
+        if !key.verify(metadata, signature) {
+            return Some(Problem::BadSignature(key.name.clone()));
+        }
+    }
+
+    if config.require_sigs && crate::signing::trusted_signers(metadata).is_empty() {
+        return Some(Problem::Unsigned);
+    }
+
+    None
+}
+
+/// Store paths in the store directory that are not registered. Hidden entries are skipped, as
+/// they are used for temporary files.
+pub fn find_unregistered(registered: &BTreeSet<&str>) -> Result<Vec<PathBuf>> {
+    let layout = crate::layout::get();
+    let mut result = Vec::new();
+
+    for entry in std::fs::read_dir(layout.store_dir())? {
+        let entry = entry?;
+        if entry.file_name().as_encoded_bytes().starts_with(b".") {
+            continue;
+        }
+        let path = layout.logical(entry.path());
+        if !registered.contains(path.to_str().unwrap_or_default()) {
+            result.push(path);
+        }
+    }
+
+    result.sort();
+    Ok(result)
+}
+
+impl crate::Main for Args {
+    fn main(&self) -> Result<()> {
+        tokio::runtime::Runtime::new()?.block_on(self._main())
+    }
+}
+
+impl Args {
+    async fn _main(&self) -> Result<()> {
+        let _lock = if self.repair {
+            Some(crate::lock::StoreLock::shared()?)
+        } else {
+            None
+        };
+        let conn = DbConnection::new()?;
+
+        let infos = match &self.path {
+            Some(path) => {
+                let path = crate::db::normalize_store_path(path);
+                let info = conn
+                    .path_info(&path)?
+                    .ok_or_else(|| eyre!("{:?} is not a registered path", path))?;
+                vec![info]
+            }
+            None => conn.list()?,
+        };
+
+        let mut problems: Vec<(String, Problem)> = Vec::new();
+
+        for info in &infos {
+            debug!(path = ?info.store_path, "Verifying");
+            let problem = match verify_path(info) {
+                Some(problem) => Some(problem),
+                None => verify_signatures(&PathMetadata::read(&conn, &info.store_path)?),
+            };
+            if let Some(problem) = problem {
+                problems.push((info.store_path.clone(), problem));
+            }
+        }
+
+        if self.all {
+            let registered = infos.iter().map(|i| i.store_path.as_str()).collect();
+            for path in find_unregistered(&registered)? {
+                problems.push((path.to_string_lossy().into_owned(), Problem::Unregistered));
+            }
+        }
+
+        for (path, problem) in &problems {
+            println!("{}: {}", path, problem);
+        }
+
+        let mut remaining = problems.iter().filter(|(_, p)| p.is_failure()).count();
+
+        if self.repair {
+            let broken: BTreeSet<MiqResult> = problems
+                .iter()
+                .filter(|(_, problem)| problem.repairable())
+                .filter_map(|(path, _)| MiqResult::from_store_path(path))
+                .collect();
+
+            let conn = Mutex::new(conn);
+            for result in repair_order(&broken)? {
+                let path = result.store_path();
+                info!(?path, "Repairing");
+
+                let unit = Unit::from_result(&result)
+                    .suggestion("The unit is gone from the eval directory, evaluate it again")?;
+
+                let pb = ProgressBar::new_spinner();
+                pb.set_message(result.name().to_owned());
+                unit.build(true, &conn, pb.clone()).await?;
+                pb.finish_and_clear();
+
+                println!("{}: repaired", path.to_string_lossy());
+                remaining -= 1;
+            }
+        }
+
+        if remaining > 0 {
+            let err = eyre!("{} problems found", remaining);
+            if self.repair {
+                return Err(err.suggestion("Unregistered paths can be deleted by hand"));
+            }
+            return Err(err.suggestion("Use --repair to rebuild the broken paths"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Order the paths so that the dependencies of a unit are repaired before it
+fn repair_order(broken: &BTreeSet<MiqResult>) -> Result<Vec<MiqResult>> {
+    let mut keyed = Vec::new();
+    for result in broken {
+        let closure = crate::eval::deps_closure(&BTreeSet::from([result.clone()]))
+            .wrap_err(format!("Can't repair {}", result.as_str()))?;
+        // A broken dependency has fewer broken paths in its closure than its dependents
+        let broken_deps = closure.intersection(broken).count();
+        keyed.push((broken_deps, result.clone()));
+    }
+
+    keyed.sort();
+    Ok(keyed.into_iter().map(|(_, result)| result).collect())
+}
+
+#[test]
+fn test_verify_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("out");
+    std::fs::write(&path, "hello").unwrap();
+
+    let (integrity, _) = crate::archive::hash_path(&path).unwrap();
+    let mut info = StorePath {
+        store_path: path.to_str().unwrap().to_owned(),
+        registered_at: 0,
+        archive_hash: Some(integrity.to_string()),
+        archive_size: None,
+    };
+    assert_eq!(verify_path(&info), None);
+
+    std::fs::write(&path, "hell").unwrap();
+    assert!(matches!(verify_path(&info), Some(Problem::Mismatch { .. })));
+
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(verify_path(&info), Some(Problem::Missing));
+
+    std::fs::write(&path, "hello").unwrap();
+    info.archive_hash = None;
+    assert_eq!(verify_path(&info), Some(Problem::NoHash));
+}
@@ -0,0 +1,51 @@
+//! Runs the binary in rootless mode, which can't be tested in-process: a user namespace can only
+//! be entered by a process with a single thread.
+
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+use nix::sched::CloneFlags;
+
+/// Whether this system lets unprivileged users create user namespaces
+fn userns_available() -> bool {
+    let mut command = Command::new("true");
+    unsafe {
+        command.pre_exec(|| {
+            nix::sched::unshare(CloneFlags::CLONE_NEWUSER).map_err(std::io::Error::from)
+        });
+    }
+    command.status().is_ok_and(|status| status.success())
+}
+
+fn miq(store: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_miq"));
+    command
+        .env("MIQ_CONFIG", store.join("config.toml"))
+        .env("NO_COLOR", "1")
+        .arg("--rootless")
+        .arg("--store")
+        .arg(store);
+    command
+}
+
+#[test]
+fn test_rootless_enter() {
+    if !userns_available() {
+        eprintln!("Skipping, unprivileged user namespaces are not available");
+        return;
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let store = dir.path();
+
+    let status = miq(store)
+        .args(["enter", "--", "sh", "-c"])
+        .arg("test -d /miq/store && touch /miq/store/marker")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert!(store.join("store/marker").exists());
+    assert!(store.join(".rootfs").is_dir());
+    assert_eq!(std::fs::read_dir(store.join(".rootfs")).unwrap().count(), 0);
+}